@@ -1,11 +1,13 @@
+require 'zlib'
+
 describe 'database' do
   before do
-    `rm -rf test.db`
+    `rm -rf test.db import.csv salvage.db copy.db backup.db`
   end
 
-  def run_script(commands)
+  def run_script(commands, options = "", filename = "test.db")
     raw_output = nil
-    IO.popen("./target/debug/my_sqlite test.db", "r+") do |pipe|
+    IO.popen("./target/debug/my_sqlite #{options} #{filename}", "r+") do |pipe|
       commands.each do |command|
         begin
           pipe.puts command
@@ -93,6 +95,22 @@ describe 'database' do
     ])
   end
 
+  it 'prints an error message if parameters are not bound' do
+    script = [
+      "insert ? :name ?5",
+      "insert ?0 user1 person1@example.com",
+      "select",
+      ".exit",
+    ]
+    result = run_script(script)
+    expect(result).to match_array([
+      "db > Error: Expected 5 parameters but got 0.",
+      "db > Syntax error. Could not parse statement",
+      "db > Executed.",
+      "db > ",
+    ])
+  end
+
   it 'keeps data after closing connection' do
     result1 = run_script([
       "insert 1 user1 person1@example.com",
@@ -126,12 +144,73 @@ describe 'database' do
       "COMMON_NODE_HEADER_SIZE: 6",
       "LEAF_NODE_HEADER_SIZE: 14",
       "LEAF_NODE_CELL_SIZE: 295",
-      "LEAF_NODE_SPACE_FOR_CELLS: 4082",
+      "LEAF_NODE_SPACE_FOR_CELLS: 4078",
       "LEAF_NODE_MAX_CELLS: 13",
       "db > ",
     ])
   end
 
+  it 'keeps the page size chosen when the database was created' do
+    result1 = run_script([
+      ".constants",
+      "insert 1 user1 person1@example.com",
+      ".exit",
+    ], "--page-size 1024")
+    expect(result1).to include("LEAF_NODE_SPACE_FOR_CELLS: 1006", "LEAF_NODE_MAX_CELLS: 3")
+
+    result2 = run_script([
+      ".constants",
+      "select",
+      ".exit",
+    ])
+    expect(result2).to include("LEAF_NODE_MAX_CELLS: 3", "db > (1, user1, person1@example.com)")
+  end
+
+  it 'rejects an invalid page size' do
+    result = run_script([".exit"], "--page-size 1000")
+    expect(result).to match_array([
+      "Page size must be a power of two between 512 and 65536.",
+    ])
+  end
+
+  it 'refuses to open a file that is not a database' do
+    File.write("test.db", "not a database")
+    result = run_script([".exit"])
+    expect(result).to match_array([
+      "File is not a database.",
+    ])
+  end
+
+  it 'tells a file from before the header apart from one that is not a database' do
+    # Files from before the header start with a root leaf on page 0
+    page = "\x01\x01".b + "\x00".b * 4094
+    File.binwrite("test.db", page)
+    result = run_script([".exit"])
+    expect(result).to match_array([
+      "File was created by an older version; export its rows with that version and re-import them.",
+    ])
+  end
+
+  it 'reports a corrupt page instead of crashing' do
+    run_script([
+      "insert 1 user1 person1@example.com",
+      ".exit",
+    ])
+    # Overwrite the node type byte of the root page
+    File.binwrite("test.db", "\x07", 4096)
+
+    result = run_script([
+      "select",
+      "insert 2 user2 person2@example.com",
+      ".exit",
+    ])
+    expect(result).to match_array([
+      "db > Error: Page 1 is corrupt.",
+      "db > Error: Page 1 is corrupt.",
+      "db > ",
+    ])
+  end
+
   it 'allows printing out the structure of a one-node btree' do
     script = [3, 1, 2].map do |i|
       "insert #{i} user#{i} person#{i}@example.com"
@@ -166,7 +245,7 @@ describe 'database' do
     expect(result[14..(result.length)]).to match_array([
       "db > Tree:",
       "- internal (size 1)",
-      "  - leaf (size 7)",
+      "  - leaf (size 13)",
       "    - 1",
       "    - 2",
       "    - 3",
@@ -174,20 +253,39 @@ describe 'database' do
       "    - 5",
       "    - 6",
       "    - 7",
-      "  - key 7",
-      "  - leaf (size 7)",
       "    - 8",
       "    - 9",
       "    - 10",
       "    - 11",
       "    - 12",
       "    - 13",
+      "  - key 13",
+      "  - leaf (size 1)",
       "    - 14",
       "db > Executed.",
       "db > ",
     ])
   end
 
+  it 'keeps leaves full when inserting keys in ascending order' do
+    script = (1..40).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".stats"
+    script << ".exit"
+    result = run_script(script)
+
+    expect(result.last(7)).to match_array([
+      "db > Space usage:",
+      "Leaf pages: 4",
+      "Internal pages: 1",
+      "Rows: 40",
+      "Leaf utilization: 76.9%",
+      "Internal utilization: 100.0%",
+      "db > ",
+    ])
+  end
+
   it 'prints all rows in a multi-level tree' do
     script = []
     (1..15).each do |i|
@@ -452,4 +550,787 @@ describe 'database' do
       "db > ",
     ])
   end
+
+  def write_rows(filename, ids)
+    File.write(filename, ids.map { |i| "#{i},user#{i},person#{i}@example.com\n" }.join)
+  end
+
+  it 'bulk loads sorted rows into packed leaves' do
+    write_rows("import.csv", (1..20))
+    script = [
+      ".import --sorted --fill 100 import.csv",
+      ".btree",
+      "insert 7 user7 person7@example.com",
+      ".exit",
+    ]
+    result = run_script(script)
+    expect(result).to match_array([
+      "db > Imported 20 rows.",
+      "db > Tree:",
+      "- internal (size 1)",
+      "  - leaf (size 13)",
+      *(1..13).map { |i| "    - #{i}" },
+      "  - key 13",
+      "  - leaf (size 7)",
+      *(14..20).map { |i| "    - #{i}" },
+      "db > Error: Duplicated key.",
+      "db > ",
+    ])
+  end
+
+  it 'keeps bulk loaded rows after closing connection' do
+    write_rows("import.csv", (1..40))
+    run_script([
+      ".import --sorted import.csv",
+      ".exit",
+    ])
+    result = run_script([
+      "select",
+      ".exit",
+    ])
+    expect(result.length).to eq(42)
+    expect(result.first).to eq("db > (1, user1, person1@example.com)")
+    expect(result[39]).to eq("(40, user40, person40@example.com)")
+  end
+
+  it 'rejects unsorted rows in a sorted import' do
+    write_rows("import.csv", [3, 1, 2])
+    result = run_script([
+      ".import --sorted import.csv",
+      ".import import.csv",
+      "select",
+      ".exit",
+    ])
+    expect(result).to match_array([
+      "db > Error: Keys are not sorted.",
+      "db > Imported 3 rows.",
+      "db > (1, user1, person1@example.com)",
+      "(2, user2, person2@example.com)",
+      "(3, user3, person3@example.com)",
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'keeps an in-memory database only until it is closed' do
+    result1 = run_script([
+      "insert 1 user1 person1@example.com",
+      "select",
+      ".exit",
+    ], "", ":memory:")
+    expect(result1).to match_array([
+      "db > Executed.",
+      "db > (1, user1, person1@example.com)",
+      "Executed.",
+      "db > ",
+    ])
+
+    result2 = run_script([
+      "select",
+      ".exit",
+    ], "", ":memory:")
+    expect(result2).to match_array([
+      "db > Executed.",
+      "db > ",
+    ])
+    expect(File.exist?(":memory:")).to be false
+    expect(File.exist?("test.db")).to be false
+  end
+
+  it 'refuses to modify a database opened read-only' do
+    run_script([
+      "insert 1 user1 person1@example.com",
+      ".exit",
+    ])
+    before = File.binread("test.db")
+
+    result = run_script([
+      "insert 2 user2 person2@example.com",
+      "select",
+      ".exit",
+    ], "--read-only")
+    expect(result).to match_array([
+      "db > Error: Attempt to write a read-only database.",
+      "db > (1, user1, person1@example.com)",
+      "Executed.",
+      "db > ",
+    ])
+    expect(File.binread("test.db")).to eq(before)
+  end
+
+  it 'does not create a missing file when opened read-only' do
+    result = run_script([".exit"], "--read-only")
+    expect(result).to eq(["Error: No such file or directory (os error 2)"])
+    expect(File.exist?("test.db")).to be false
+  end
+
+  it 'reports a busy database while another connection is writing' do
+    IO.popen("./target/debug/my_sqlite test.db", "r+") do |writer|
+      writer.puts "insert 1 user1 person1@example.com"
+      expect(writer.gets).to eq("db > Executed.\n")
+
+      result = run_script([
+        "insert 2 user2 person2@example.com",
+        ".exit",
+      ], "--busy-timeout 100")
+      expect(result).to match_array([
+        "db > Error: Database is busy.",
+        "db > ",
+      ])
+
+      writer.puts ".exit"
+      writer.close_write
+      writer.read
+    end
+
+    result = run_script([
+      "select",
+      ".exit",
+    ])
+    expect(result).to match_array([
+      "db > (1, user1, person1@example.com)",
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'sees rows committed by another process without reopening' do
+    IO.popen("./target/debug/my_sqlite test.db", "r+") do |reader|
+      reader.puts "select"
+      expect(reader.gets).to eq("db > Executed.\n")
+
+      run_script([
+        "insert 1 user1 person1@example.com",
+        ".exit",
+      ])
+
+      reader.puts "select"
+      expect(reader.gets).to eq("db > (1, user1, person1@example.com)\n")
+      expect(reader.gets).to eq("Executed.\n")
+
+      reader.puts ".exit"
+      reader.close_write
+      reader.read
+    end
+  end
+
+  it 'keeps a copy-on-write tree across connections' do
+    keys = (1..30).to_a.shuffle(random: Random.new(42))
+    script = keys.map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    result = run_script(script, "--copy-on-write")
+    expect(result.last(2)).to match_array([
+      "db > Executed.",
+      "db > ",
+    ])
+
+    # The storage mode is recorded in the file, so it doesn't need to be given again
+    result = run_script([
+      "insert 31 user31 person31@example.com",
+      "select",
+      ".exit",
+    ])
+    expected_rows = (1..31).map do |i|
+      "(#{i}, user#{i}, person#{i}@example.com)"
+    end
+    expect(result).to eq([
+      "db > Executed.",
+      "db > #{expected_rows[0]}",
+      *expected_rows[1..],
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'reuses the pages a copy-on-write insert replaces' do
+    # Each insert copies a whole path, so without reusing pages the table would be
+    # full long before the last insert
+    keys = (1..300).to_a.shuffle(random: Random.new(7))
+    keys.each_slice(100) do |slice|
+      script = slice.map do |i|
+        "insert #{i} user#{i} person#{i}@example.com"
+      end
+      script << ".exit"
+      result = run_script(script, "--copy-on-write")
+      expect(result.uniq).to match_array([
+        "db > Executed.",
+        "db > ",
+      ])
+    end
+
+    result = run_script([
+      "select",
+      ".exit",
+    ])
+    expect(result.length).to eq(302)
+  end
+
+  it 'rolls back to a savepoint and keeps what came before it' do
+    result = run_script([
+      "insert 1 user1 person1@example.com",
+      "savepoint outer",
+      "insert 2 user2 person2@example.com",
+      "savepoint inner",
+      "insert 3 user3 person3@example.com",
+      "release inner",
+      "insert 4 user4 person4@example.com",
+      "rollback to outer",
+      "insert 5 user5 person5@example.com",
+      "select",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Executed.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > (1, user1, person1@example.com)",
+      "(5, user5, person5@example.com)",
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'rolls back inserts that split nodes' do
+    script = (1..20).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << "savepoint a"
+    script += (21..60).to_a.shuffle(random: Random.new(3)).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << "rollback to a"
+    script << ".exit"
+    run_script(script)
+
+    result = run_script([
+      "select",
+      ".exit",
+    ])
+    expected_rows = (1..20).map do |i|
+      "(#{i}, user#{i}, person#{i}@example.com)"
+    end
+    expect(result).to eq([
+      "db > #{expected_rows[0]}",
+      *expected_rows[1..],
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'rolls back a copy-on-write tree to its old root' do
+    script = (1..20).to_a.shuffle(random: Random.new(5)).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << "savepoint a"
+    script += (21..40).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << "rollback to a"
+    script << "insert 41 user41 person41@example.com"
+    script << ".exit"
+    run_script(script, "--copy-on-write")
+
+    result = run_script([
+      "select",
+      ".exit",
+    ])
+    expected_rows = [*1..20, 41].map do |i|
+      "(#{i}, user#{i}, person#{i}@example.com)"
+    end
+    expect(result).to eq([
+      "db > #{expected_rows[0]}",
+      *expected_rows[1..],
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'prints an error for a savepoint that was never set' do
+    result = run_script([
+      "savepoint a",
+      "release a",
+      "rollback to a",
+      "release b",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Executed.",
+      "db > Executed.",
+      "db > Error: No such savepoint: a.",
+      "db > Error: No such savepoint: b.",
+      "db > ",
+    ])
+  end
+
+  it 'keeps written rows at every synchronous level' do
+    ["off", "normal", "full"].each do |level|
+      ["", "--copy-on-write"].each do |mode|
+        File.delete("test.db") if File.exist?("test.db")
+        run_script([
+          "insert 1 user1 person1@example.com",
+          "insert 2 user2 person2@example.com",
+          ".exit",
+        ], "--synchronous #{level} #{mode}")
+
+        result = run_script([
+          "select",
+          ".exit",
+        ])
+        expect(result).to eq([
+          "db > (1, user1, person1@example.com)",
+          "(2, user2, person2@example.com)",
+          "Executed.",
+          "db > ",
+        ])
+      end
+    end
+  end
+
+  it 'rejects an unknown synchronous level' do
+    result = run_script([".exit"], "--synchronous sometimes")
+    expect(result).to match_array([
+      "Synchronous must be off, normal or full.",
+    ])
+  end
+
+  it 'reports a page whose checksum does not match' do
+    run_script([
+      "insert 1 user1 person1@example.com",
+      ".exit",
+    ])
+    # Change a letter of the username, which still leaves a valid node
+    File.binwrite("test.db", "X", 4096 + 20)
+
+    result = run_script([
+      "select",
+      ".exit",
+    ])
+    expect(result).to match_array([
+      "db > Error: Page 1 is corrupt.",
+      "db > ",
+    ])
+  end
+
+  it 'finds nothing wrong with trees built either way' do
+    ["", "--copy-on-write"].each do |mode|
+      File.delete("test.db") if File.exist?("test.db")
+      script = (1..50).to_a.shuffle(random: Random.new(11)).map do |i|
+        "insert #{i} user#{i} person#{i}@example.com"
+      end
+      script << ".exit"
+      run_script(script, mode)
+
+      result = run_script([
+        ".check",
+        "pragma integrity_check",
+        ".exit",
+      ])
+      expect(result).to eq([
+        "db > ok",
+        "db > (ok)",
+        "Executed.",
+        "db > ",
+      ])
+    end
+  end
+
+  it 'reports keys out of order on a page with a valid checksum' do
+    run_script([
+      "insert 1 user1 person1@example.com",
+      "insert 2 user2 person2@example.com",
+      "insert 3 user3 person3@example.com",
+      ".exit",
+    ])
+    # Change the first key of the root leaf and fix up the page's checksum
+    page = File.binread("test.db", 4096, 4096)
+    page[14, 4] = [5].pack("V")
+    page[4092, 4] = [Zlib.crc32(page[0, 4092])].pack("V")
+    File.binwrite("test.db", page, 4096)
+
+    result = run_script([
+      ".check",
+      "pragma integrity_check",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Page 1: key 2 is out of order after 5",
+      "db > (Page 1: key 2 is out of order after 5)",
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'reports a child pointer past the last page as corrupt' do
+    script = (1..30).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script)
+    # Point the root's first child past any page a table can have
+    page = File.binread("test.db", 4096, 4096)
+    page[14, 4] = [500].pack("V")
+    page[4092, 4] = [Zlib.crc32(page[0, 4092])].pack("V")
+    File.binwrite("test.db", page, 4096)
+
+    result = run_script([
+      "select",
+      "insert 1 user1 person1@example.com",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Error: Page 500 is corrupt.",
+      "db > Error: Page 500 is corrupt.",
+      "db > ",
+    ])
+  end
+
+  it 'reports a leaf claiming more cells than fit as corrupt' do
+    run_script([
+      "insert 1 user1 person1@example.com",
+      "insert 2 user2 person2@example.com",
+      ".exit",
+    ])
+    page = File.binread("test.db", 4096, 4096)
+    page[6, 4] = [1000].pack("V")
+    page[4092, 4] = [Zlib.crc32(page[0, 4092])].pack("V")
+    File.binwrite("test.db", page, 4096)
+
+    result = run_script([
+      "select",
+      "insert 3 user3 person3@example.com",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Error: Page 1 is corrupt.",
+      "db > Error: Page 1 is corrupt.",
+      "db > ",
+    ])
+  end
+
+  it 'checks a database file with db-check' do
+    script = (1..30).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script)
+
+    output = `./target/debug/db-check test.db`
+    expect($?.success?).to be true
+    expect(output.split("\n")).to eq(["ok"])
+  end
+
+  it 'salvages the rows under a corrupt node with db-check' do
+    script = (1..30).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script)
+    # Overwrite the node type byte of the root, which is an internal node
+    File.binwrite("test.db", "\x07", 4096)
+
+    output = `./target/debug/db-check --salvage salvage.db test.db`
+    expect($?.success?).to be false
+    expect(output.split("\n")).to eq([
+      "Page 1: corrupt",
+      "Salvaged 30 rows from 3 leaf pages into salvage.db.",
+      "Skipped unreadable pages: 1",
+    ])
+
+    result = run_script([
+      ".check",
+      "select",
+      ".exit",
+    ], "", "salvage.db")
+    expected_rows = (1..30).map do |i|
+      "(#{i}, user#{i}, person#{i}@example.com)"
+    end
+    expect(result).to eq([
+      "db > ok",
+      "db > #{expected_rows[0]}",
+      *expected_rows[1..],
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'salvages rows from a file whose header is damaged with db-check' do
+    script = (1..30).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script, "--page-size 1024")
+    File.binwrite("test.db", "not a header", 0)
+
+    output = `./target/debug/db-check --salvage salvage.db test.db`
+    expect($?.success?).to be false
+    expect(output.split("\n")).to eq([
+      "File is not a database.",
+      "The header is damaged; reading 1024-byte pages.",
+      "Salvaged 30 rows from 10 leaf pages into salvage.db.",
+    ])
+
+    result = run_script([
+      "select",
+      ".exit",
+    ], "", "salvage.db")
+    expect(result.length).to eq(32)
+  end
+
+  it 'gives up salvaging a file with no header and no checksums' do
+    File.binwrite("test.db", "not a database" * 100)
+
+    output = `./target/debug/db-check --salvage salvage.db test.db`
+    expect($?.success?).to be false
+    expect(output.split("\n")).to eq([
+      "File is not a database.",
+      "The header is damaged and no page size fits the file's checksums.",
+    ])
+    expect(File.exist?("salvage.db")).to be false
+  end
+
+  it 'shrinks the file with vacuum' do
+    script = (1..60).to_a.shuffle(random: Random.new(13)).map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script)
+    size_before = File.size("test.db")
+
+    result = run_script([
+      "vacuum",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Executed.",
+      "db > ",
+    ])
+    expect(File.size("test.db")).to be < size_before
+
+    result = run_script([
+      ".check",
+      "select",
+      ".exit",
+    ])
+    expected_rows = (1..60).map do |i|
+      "(#{i}, user#{i}, person#{i}@example.com)"
+    end
+    expect(result).to eq([
+      "db > ok",
+      "db > #{expected_rows[0]}",
+      *expected_rows[1..],
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'writes a compacted copy with vacuum into' do
+    result = run_script([
+      "insert 1 user1 person1@example.com",
+      "insert 2 user2 person2@example.com",
+      "vacuum into 'copy.db'",
+      "vacuum into 'copy.db'",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Executed.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > Error: copy.db already exists",
+      "db > ",
+    ])
+
+    result = run_script([
+      "select",
+      ".exit",
+    ], "", "copy.db")
+    expect(result).to eq([
+      "db > (1, user1, person1@example.com)",
+      "(2, user2, person2@example.com)",
+      "Executed.",
+      "db > ",
+    ])
+  end
+
+  it 'refuses to vacuum while a savepoint is set' do
+    result = run_script([
+      "savepoint a",
+      "vacuum",
+      "release a",
+      "vacuum",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Executed.",
+      "db > Error: Cannot vacuum while a savepoint is set.",
+      "db > Executed.",
+      "db > Executed.",
+      "db > ",
+    ])
+  end
+
+  # Fills a copy-on-write file in two connections. The second copies every path
+  # the first wrote, leaving its pages free once it closes.
+  def fill_with_free_pages(auto_vacuum)
+    keys = (1..120).to_a.shuffle(random: Random.new(17))
+    script = ["pragma auto_vacuum=#{auto_vacuum}"]
+    script += keys[0...40].map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script, "--copy-on-write")
+
+    script = keys[40..].map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script)
+  end
+
+  it 'cuts free pages off the end of the file with incremental vacuum' do
+    fill_with_free_pages("incremental")
+    size_before = File.size("test.db")
+
+    result = run_script([
+      "pragma incremental_vacuum(1)",
+      "pragma incremental_vacuum",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Executed.",
+      "db > Executed.",
+      "db > ",
+    ])
+    expect(File.size("test.db")).to be < size_before
+
+    result = run_script([
+      ".check",
+      "select",
+      ".exit",
+    ])
+    expect(result.first).to eq("db > ok")
+    expect(result.length).to eq(122)
+  end
+
+  it 'moves pages and cuts off free ones at close with full auto-vacuum' do
+    fill_with_free_pages("none")
+    size_without = File.size("test.db")
+    File.delete("test.db")
+
+    fill_with_free_pages("full")
+    expect(File.size("test.db")).to be < size_without
+
+    result = run_script([
+      ".check",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > ok",
+      "db > ",
+    ])
+  end
+
+  it 'only vacuums incrementally when auto-vacuum is incremental' do
+    fill_with_free_pages("none")
+    size_before = File.size("test.db")
+
+    result = run_script([
+      "pragma incremental_vacuum",
+      "pragma auto_vacuum=sometimes",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Executed.",
+      "db > Syntax error. Could not parse statement",
+      "db > ",
+    ])
+    expect(File.size("test.db")).to eq(size_before)
+  end
+
+  it 'keeps the auto-vacuum setting through a vacuum' do
+    keys = (1..120).to_a.shuffle(random: Random.new(17))
+    script = ["pragma auto_vacuum=incremental"]
+    script += keys[0...40].map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script, "--copy-on-write")
+    run_script([
+      "vacuum",
+      ".exit",
+    ])
+
+    script = keys[40..].map do |i|
+      "insert #{i} user#{i} person#{i}@example.com"
+    end
+    script << ".exit"
+    run_script(script)
+    size_before = File.size("test.db")
+
+    result = run_script([
+      "pragma incremental_vacuum",
+      ".exit",
+    ])
+    expect(result).to eq([
+      "db > Executed.",
+      "db > ",
+    ])
+    expect(File.size("test.db")).to be < size_before
+
+    result = run_script([
+      ".check",
+      "select",
+      ".exit",
+    ])
+    expect(result.first).to eq("db > ok")
+    expect(result.length).to eq(122)
+  end
+
+  it 'backs up the database with changes not yet written back' do
+    ["", "--copy-on-write"].each do |mode|
+      `rm -rf test.db backup.db`
+      script = (1..30).to_a.shuffle(random: Random.new(23)).map do |i|
+        "insert #{i} user#{i} person#{i}@example.com"
+      end
+      script << ".exit"
+      run_script(script, mode)
+
+      result = run_script([
+        "insert 31 user31 person31@example.com",
+        ".backup backup.db",
+        ".backup backup.db",
+        "insert 32 user32 person32@example.com",
+        ".exit",
+      ])
+      expect(result).to eq([
+        "db > Executed.",
+        "db > db > Error: backup.db already exists",
+        "db > Executed.",
+        "db > ",
+      ])
+
+      result = run_script([
+        ".check",
+        "select",
+        ".exit",
+      ], "", "backup.db")
+      expected_rows = (1..31).map do |i|
+        "(#{i}, user#{i}, person#{i}@example.com)"
+      end
+      expect(result).to eq([
+        "db > ok",
+        "db > #{expected_rows[0]}",
+        *expected_rows[1..],
+        "Executed.",
+        "db > ",
+      ])
+    end
+  end
 end
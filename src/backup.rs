@@ -0,0 +1,128 @@
+use crate::error::Result;
+use crate::table::Table;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+
+// How many pages `backup` copies before letting writers in
+pub const BACKUP_STEP_PAGES: u32 = 8;
+
+// Copies a database that may be in use into a new file, a few pages at a time. Each
+// step holds the source's shared lock only while it copies, so other connections
+// can write in between. Pages are read through the source connection's cache, so
+// its own changes not yet written back are copied too. If anything changes between
+// steps the copy starts over, so the finished file is the database as it was at a
+// single moment.
+pub struct Backup {
+    path: String,
+    dest: File,
+    // The source's data version when the copy in progress started
+    data_version: Option<u64>,
+    page_size: u32,
+    page_count: u32,
+    next_page: u32,
+    restarts: u32,
+}
+
+impl Backup {
+    // Creates the file the backup is written to, which must not exist yet
+    pub fn new(path: &str) -> Result<Self> {
+        let dest = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(dest) => dest,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                let message = format!("{} already exists", path);
+                return Err(std::io::Error::new(e.kind(), message).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_string(),
+            dest,
+            data_version: None,
+            page_size: 0,
+            page_count: 0,
+            next_page: 0,
+            restarts: 0,
+        })
+    }
+
+    // Copies up to `max_pages` more pages from `table`, returning true once the
+    // backup is complete and on disk
+    pub fn step(&mut self, table: &mut Table, max_pages: u32) -> Result<bool> {
+        table.begin_read()?;
+        let result = self.copy_pages(table, max_pages);
+        table.end_read()?;
+        result
+    }
+
+    fn copy_pages(&mut self, table: &mut Table, max_pages: u32) -> Result<bool> {
+        let pager = table.pager();
+        let data_version = pager.data_version();
+        if self.data_version != Some(data_version) {
+            if self.data_version.is_some() {
+                self.restarts += 1;
+            }
+            self.data_version = Some(data_version);
+            self.page_size = pager.page_size();
+            self.page_count = pager.num_pages();
+            self.next_page = 0;
+        }
+
+        let end = self
+            .page_count
+            .min(self.next_page.saturating_add(max_pages));
+        for page_num in self.next_page..end {
+            let page = pager.page_image(page_num)?;
+            self.dest
+                .write_all_at(&page, page_num as u64 * self.page_size as u64)?;
+        }
+        self.next_page = end;
+        if self.next_page < self.page_count {
+            return Ok(false);
+        }
+
+        // A copy that started over may have found the database smaller
+        self.dest
+            .set_len(self.page_count as u64 * self.page_size as u64)?;
+        self.dest.sync_all()?;
+        Ok(true)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Pages in the database being copied, as of the copy in progress
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    pub fn remaining(&self) -> u32 {
+        self.page_count - self.next_page
+    }
+
+    // How many times the source changed part way through and the copy started over
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+}
+
+// Backs up the database to a new file at `path`, a step at a time. A backup that
+// fails part way is removed.
+pub fn backup(table: &mut Table, path: &str) -> Result<Backup> {
+    let mut backup = Backup::new(path)?;
+    loop {
+        match backup.step(table, BACKUP_STEP_PAGES) {
+            Ok(true) => return Ok(backup),
+            Ok(false) => {}
+            Err(e) => {
+                let _ = std::fs::remove_file(path);
+                return Err(e);
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use libc::{EXIT_FAILURE, EXIT_SUCCESS};
+use my_sqlite::bulk_load::DEFAULT_FILL_FACTOR;
+use my_sqlite::header::StorageMode;
+use my_sqlite::pager::{Pager, DEFAULT_PAGE_SIZE};
+use my_sqlite::salvage::{guess_page_size, salvage_file_rows, salvage_rows};
+use my_sqlite::{Connection, DbError, Result};
+use std::env;
+use std::fs::File;
+use std::path::Path;
+use std::process::exit;
+
+// db-check [--salvage <output>] <filename>
+//
+// Checks the tree in a database file without changing it, printing "ok" or every
+// problem found. With --salvage, also copies every row that can still be read into
+// a new database file, even when the header is too damaged to open the file. Exits
+// with failure if the file has problems.
+fn main() {
+    let mut filename = None;
+    let mut salvage_to = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--salvage" => match args.next() {
+                Some(output) => salvage_to = Some(output),
+                None => {
+                    println!("Must supply a file to salvage rows into.");
+                    exit(EXIT_FAILURE);
+                }
+            },
+            _ => filename = Some(arg),
+        }
+    }
+
+    let Some(filename) = filename else {
+        println!("Must supply a database filename.");
+        exit(EXIT_FAILURE);
+    };
+
+    let sound = match check(&filename) {
+        Ok(problems) if problems.is_empty() => {
+            println!("ok");
+            true
+        }
+        Ok(problems) => {
+            for problem in problems {
+                println!("{}", problem);
+            }
+            false
+        }
+        Err(e) => {
+            println!("{}", e);
+            false
+        }
+    };
+
+    if let Some(output) = salvage_to {
+        if let Err(e) = salvage(&filename, &output) {
+            println!("{}", e);
+            exit(EXIT_FAILURE);
+        }
+    }
+    exit(if sound { EXIT_SUCCESS } else { EXIT_FAILURE });
+}
+
+fn check(filename: &str) -> Result<Vec<String>> {
+    let mut conn = Connection::open_read_only(filename)?;
+    let problems = conn.table().integrity_check()?;
+    conn.close()?;
+    Ok(problems)
+}
+
+// Reads every page through the pager rather than walking the tree, so rows under
+// damaged nodes are still found. When the header can't be read the pager can't be
+// opened, so the page size is guessed from the pages' checksums and the file is
+// read directly. Nothing can open such a file to write it, so it isn't locked.
+fn salvage(filename: &str, output: &str) -> Result<()> {
+    if Path::new(output).exists() {
+        println!("Output file '{}' already exists.", output);
+        exit(EXIT_FAILURE);
+    }
+
+    let (salvage, page_size, storage_mode) =
+        match Pager::open(filename, DEFAULT_PAGE_SIZE, StorageMode::InPlace, true) {
+            Ok(mut pager) => {
+                pager.lock_shared()?;
+                let salvage = salvage_rows(&mut pager);
+                pager.unlock_shared()?;
+                (salvage?, pager.page_size(), pager.storage_mode())
+            }
+            Err(DbError::NotADatabase) => {
+                let file = File::open(filename)?;
+                let Some(page_size) = guess_page_size(&file)? else {
+                    println!("The header is damaged and no page size fits the file's checksums.");
+                    exit(EXIT_FAILURE);
+                };
+                println!("The header is damaged; reading {}-byte pages.", page_size);
+                let salvage = salvage_file_rows(&file, page_size)?;
+                (salvage, page_size, StorageMode::InPlace)
+            }
+            Err(e) => return Err(e),
+        };
+
+    let mut conn = Connection::open_with_options(output, page_size, storage_mode, false)?;
+    conn.table().bulk_load(&salvage.rows, DEFAULT_FILL_FACTOR)?;
+    conn.close()?;
+
+    println!(
+        "Salvaged {} rows from {} leaf pages into {}.",
+        salvage.rows.len(),
+        salvage.leaf_pages,
+        output
+    );
+    if !salvage.unreadable_pages.is_empty() {
+        let pages: Vec<String> = salvage
+            .unreadable_pages
+            .iter()
+            .map(|page_num| page_num.to_string())
+            .collect();
+        println!("Skipped unreadable pages: {}", pages.join(", "));
+    }
+    Ok(())
+}
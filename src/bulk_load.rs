@@ -0,0 +1,200 @@
+use crate::error::{ConstraintError, DbError, Result};
+use crate::header::StorageMode;
+use crate::node::internal_node::InternalNode;
+use crate::node::leaf_node::LeafNode;
+use crate::node::{NodeTrait, NodeType};
+use crate::node_layout::NodeLayout;
+use crate::page::PageMut;
+use crate::pager::TABLE_MAX_PAGES;
+use crate::row::{serialize_row, Row};
+use crate::table::Table;
+
+pub const DEFAULT_FILL_FACTOR: f64 = 0.9;
+
+// Builds the whole tree bottom-up from rows sorted by key: leaves are packed to
+// `fill_factor` and chained, then each internal level is built over the one below
+// until the remaining nodes fit in the root page.
+pub fn bulk_load(table: &mut Table, rows: &[Row], fill_factor: f64) -> Result<()> {
+    if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+        return Err(DbError::InvalidFillFactor);
+    }
+
+    let root_page_num = table.root_page_num();
+    let root = LeafNode::new(table.pager().page(root_page_num)?);
+    if root.get_node_type()? != NodeType::Leaf || root.get_num_cells() != 0 {
+        return Err(ConstraintError::TableNotEmpty.into());
+    }
+
+    for pair in rows.windows(2) {
+        if pair[0].id == pair[1].id {
+            return Err(ConstraintError::DuplicateKey.into());
+        }
+        if pair[0].id > pair[1].id {
+            return Err(ConstraintError::KeysNotSorted.into());
+        }
+    }
+
+    let layout = *table.pager().layout();
+    let leaf_capacity = leaf_capacity(&layout, fill_factor);
+    let num_leaves = rows.len().div_ceil(leaf_capacity);
+    let children_per_node = children_per_internal_node(&layout, fill_factor);
+
+    // Checked before anything changes, so a load that doesn't fit leaves the table
+    // as it was
+    let copy_on_write = table.pager().storage_mode() == StorageMode::CopyOnWrite;
+    let mut pages_needed = 0;
+    if num_leaves > 1 {
+        pages_needed = num_leaves;
+        let mut level_size = num_leaves;
+        while level_size > children_per_node {
+            level_size = level_size.div_ceil(children_per_node);
+            pages_needed += level_size;
+        }
+    }
+    if copy_on_write && table.pager().reusable_pages_below(TABLE_MAX_PAGES) == 0 {
+        pages_needed += 1;
+    }
+    if table.pager().get_unused_page_num() as usize + pages_needed > TABLE_MAX_PAGES as usize {
+        return Err(DbError::Full);
+    }
+
+    if copy_on_write {
+        // Build on new pages, so the empty root stays in the file until the header
+        // switches to the loaded tree
+        let new_root = table.pager().allocate_page()?;
+        let mut root = LeafNode::new(table.pager().page_mut(new_root)?);
+        root.initialize();
+        root.set_root(true);
+        table.pager().free_page(root_page_num);
+        table.set_root_page_num(new_root)?;
+    }
+    let root_page_num = table.root_page_num();
+
+    if num_leaves <= 1 {
+        let mut root = LeafNode::new(table.pager().page_mut(root_page_num)?);
+        write_leaf(&mut root, rows);
+        return Ok(());
+    }
+
+    // (page number, max key) of every node on the level being built
+    let mut level: Vec<(u32, u32)> = Vec::with_capacity(num_leaves);
+    for chunk in rows.chunks(leaf_capacity) {
+        let pager = table.pager();
+        let page_num = pager.get_unused_page_num();
+        let mut leaf = LeafNode::new(pager.page_mut(page_num)?);
+        leaf.initialize();
+        write_leaf(&mut leaf, chunk);
+
+        if let Some(&(prev_page_num, _)) = level.last() {
+            LeafNode::new(pager.page_mut(prev_page_num)?).set_next_leaf(page_num);
+        }
+        level.push((page_num, chunk[chunk.len() - 1].id));
+    }
+
+    while level.len() > children_per_node {
+        let mut next_level = Vec::with_capacity(level.len().div_ceil(children_per_node));
+        for chunk in level.chunks(children_per_node) {
+            let page_num = table.pager().get_unused_page_num();
+            write_internal_node(table, page_num, chunk)?;
+            next_level.push((page_num, chunk[chunk.len() - 1].1));
+        }
+        level = next_level;
+    }
+
+    write_internal_node(table, root_page_num, &level)?;
+    let mut root = InternalNode::new(table.pager().page_mut(root_page_num)?);
+    root.set_root(true);
+
+    Ok(())
+}
+
+fn leaf_capacity(layout: &NodeLayout, fill_factor: f64) -> usize {
+    let max_cells = layout.leaf_node_max_cells;
+    let capacity = (max_cells as f64 * fill_factor).floor() as usize;
+    capacity.clamp(1, max_cells)
+}
+
+fn children_per_internal_node(layout: &NodeLayout, fill_factor: f64) -> usize {
+    let max_children = layout.internal_node_max_cells + 1;
+    let children = (max_children as f64 * fill_factor).floor() as usize;
+    children.clamp(2, max_children)
+}
+
+fn write_leaf(leaf: &mut LeafNode<PageMut>, rows: &[Row]) {
+    for (i, row) in rows.iter().enumerate() {
+        leaf.set_key(i as u32, row.id);
+        serialize_row(row, leaf.value_mut(i as u32));
+    }
+    leaf.set_num_cells(rows.len() as u32);
+}
+
+fn write_internal_node(table: &mut Table, page_num: u32, children: &[(u32, u32)]) -> Result<()> {
+    let mut node = InternalNode::new(table.pager().page_mut(page_num)?);
+    node.initialize();
+
+    let num_keys = children.len() as u32 - 1;
+    node.set_num_keys(num_keys);
+    for (i, &(child_page_num, child_max_key)) in children[..num_keys as usize].iter().enumerate() {
+        node.set_child(i as u32, child_page_num)?;
+        node.set_key(i as u32, child_max_key);
+    }
+    node.set_right_child(children[num_keys as usize].0);
+
+    for &(child_page_num, _) in children {
+        let mut child = LeafNode::new(table.pager().page_mut(child_page_num)?);
+        child.set_parent(page_num);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{DEFAULT_PAGE_SIZE, MEMORY_DB_NAME};
+    use crate::statement::prepare_row;
+
+    #[test]
+    fn rejects_fill_factors_outside_zero_to_one() {
+        let mut table = Table::new();
+        table
+            .db_open(
+                MEMORY_DB_NAME,
+                DEFAULT_PAGE_SIZE,
+                StorageMode::InPlace,
+                false,
+            )
+            .unwrap();
+        for fill_factor in [0.0, -0.5, 1.5, f64::NAN] {
+            let result = bulk_load(&mut table, &[], fill_factor);
+            assert!(matches!(result, Err(DbError::InvalidFillFactor)));
+        }
+        bulk_load(&mut table, &[], 1.0).unwrap();
+    }
+
+    #[test]
+    fn copy_on_write_load_that_does_not_fit_leaves_the_table_alone() {
+        let mut table = Table::new();
+        table
+            .db_open(
+                MEMORY_DB_NAME,
+                DEFAULT_PAGE_SIZE,
+                StorageMode::CopyOnWrite,
+                false,
+            )
+            .unwrap();
+        let root_page_num = table.root_page_num();
+        let rows: Vec<Row> = (1..=2000)
+            .map(|id| prepare_row(&id.to_string(), "user", "person@example.com").unwrap())
+            .collect();
+        assert!(matches!(
+            bulk_load(&mut table, &rows, 1.0),
+            Err(DbError::Full)
+        ));
+        assert_eq!(table.root_page_num(), root_page_num);
+        assert_eq!(table.pager().reusable_pages_below(TABLE_MAX_PAGES), 0);
+
+        bulk_load(&mut table, &rows[..100], 1.0).unwrap();
+        assert_eq!(table.space_usage().unwrap().rows, 100);
+        assert!(table.integrity_check().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+// Pages of files with checksums end in a CRC-32 of the rest of the page, so a page
+// that was damaged or only partly written is caught when it is read back instead
+// of being trusted
+
+use crate::page::{read_u32, write_u32};
+
+pub const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let crc = bytes.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+fn checksum_offset(page: &[u8]) -> usize {
+    page.len() - CHECKSUM_SIZE
+}
+
+pub fn write_checksum(page: &mut [u8]) {
+    let offset = checksum_offset(page);
+    let checksum = crc32(&page[..offset]);
+    write_u32(page, offset, checksum);
+}
+
+pub fn checksum_matches(page: &[u8]) -> bool {
+    let offset = checksum_offset(page);
+    read_u32(page, offset) == crc32(&page[..offset])
+}
@@ -0,0 +1,296 @@
+use crate::backup::{backup, Backup};
+use crate::cursor::{table_start, Cursor};
+use crate::error::Result;
+use crate::header::StorageMode;
+use crate::pager::{Synchronous, DEFAULT_PAGE_SIZE, MEMORY_DB_NAME};
+use crate::row_serde::{from_values, to_values};
+use crate::statement::{
+    bind_row, integrity_check_rows, prepare_statement, row_from_values, Parameters,
+    Statement as ParsedStatement,
+};
+use crate::table::Table;
+use crate::value::Value;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+// An open database file. Pages are written back when the connection is closed or dropped.
+pub struct Connection {
+    table: Table,
+}
+
+impl Connection {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_with_page_size(path, DEFAULT_PAGE_SIZE)
+    }
+
+    // A database that lives only in memory and is gone once the connection closes
+    pub fn open_in_memory() -> Result<Self> {
+        Self::open_with_page_size(MEMORY_DB_NAME, DEFAULT_PAGE_SIZE)
+    }
+
+    // Opens an existing database without write access. Mutating statements fail
+    // with `DbError::ReadOnly` and nothing is written back on close.
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        Self::open_with_options(path, DEFAULT_PAGE_SIZE, StorageMode::InPlace, true)
+    }
+
+    // `page_size` is only used when creating a new database file
+    pub fn open_with_page_size(path: &str, page_size: u32) -> Result<Self> {
+        Self::open_with_options(path, page_size, StorageMode::InPlace, false)
+    }
+
+    // `page_size` and `storage_mode` are only used when creating a new database file
+    pub fn open_with_options(
+        path: &str,
+        page_size: u32,
+        storage_mode: StorageMode,
+        read_only: bool,
+    ) -> Result<Self> {
+        let mut table = Table::new();
+        table.db_open(path, page_size, storage_mode, read_only)?;
+        Ok(Self { table })
+    }
+
+    pub fn close(mut self) -> Result<()> {
+        self.table.db_close()
+    }
+
+    pub fn prepare(&mut self, sql: &str) -> Result<Statement<'_>> {
+        let (statement, params) = prepare_statement(sql)?;
+        Ok(Statement {
+            conn: self,
+            statement,
+            params,
+        })
+    }
+
+    // Prepares and runs a statement, returning the number of rows it changed
+    pub fn execute(&mut self, sql: &str, params: &[Value]) -> Result<usize> {
+        self.prepare(sql)?.execute(params)
+    }
+
+    // Inserts a row whose columns are the fields of `value`, matched by name
+    pub fn insert_struct<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let values = to_values(value)?;
+        let row = row_from_values(&values[0], &values[1], &values[2])?;
+        self.table.insert(row)
+    }
+
+    // How long to wait for another connection's lock before giving up with
+    // `DbError::DatabaseBusy`
+    pub fn set_busy_timeout(&mut self, timeout: Duration) {
+        self.table.pager().set_busy_timeout(timeout);
+    }
+
+    // How long closing waits for the disk. See `Synchronous` for what each level
+    // guarantees.
+    pub fn set_synchronous(&mut self, synchronous: Synchronous) {
+        self.table.pager().set_synchronous(synchronous);
+    }
+
+    // Copies the database into a new file while other connections may be writing
+    // to it. See `Backup` for copying a step at a time.
+    pub fn backup(&mut self, path: &str) -> Result<Backup> {
+        backup(&mut self.table, path)
+    }
+
+    pub fn table(&mut self) -> &mut Table {
+        &mut self.table
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.table.db_close();
+    }
+}
+
+// A parsed statement that can be run any number of times with different parameters
+pub struct Statement<'conn> {
+    conn: &'conn mut Connection,
+    statement: ParsedStatement,
+    params: Parameters,
+}
+
+impl Statement<'_> {
+    pub fn parameter_count(&self) -> usize {
+        self.params.count()
+    }
+
+    // The 1-based index of a `:name` parameter
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.params.index(name)
+    }
+
+    // Runs the statement and returns the number of rows it changed
+    pub fn execute(&mut self, params: &[Value]) -> Result<usize> {
+        let values = self.params.bind(params)?;
+        self.execute_bound(&values)
+    }
+    pub fn execute_named(&mut self, params: &[(&str, Value)]) -> Result<usize> {
+        let values = self.params.bind_named(params)?;
+        self.execute_bound(&values)
+    }
+
+    // Runs the statement and returns the rows it produces. Statements that don't
+    // produce rows are executed and return no rows.
+    pub fn query(&mut self, params: &[Value]) -> Result<Rows<'_>> {
+        let values = self.params.bind(params)?;
+        self.query_bound(&values)
+    }
+    pub fn query_named(&mut self, params: &[(&str, Value)]) -> Result<Rows<'_>> {
+        let values = self.params.bind_named(params)?;
+        self.query_bound(&values)
+    }
+
+    fn execute_bound(&mut self, values: &[Value]) -> Result<usize> {
+        match &self.statement {
+            ParsedStatement::Insert(operands) => {
+                let row = bind_row(operands, values)?;
+                self.conn.table.insert(row)?;
+                Ok(1)
+            }
+            ParsedStatement::Select | ParsedStatement::IntegrityCheck => Ok(0),
+            ParsedStatement::Savepoint(name) => {
+                self.conn.table.savepoint(name);
+                Ok(0)
+            }
+            ParsedStatement::Release(name) => {
+                self.conn.table.release(name)?;
+                Ok(0)
+            }
+            ParsedStatement::RollbackTo(name) => {
+                self.conn.table.rollback_to(name)?;
+                Ok(0)
+            }
+            ParsedStatement::AutoVacuum(auto_vacuum) => {
+                self.conn.table.set_auto_vacuum(*auto_vacuum)?;
+                Ok(0)
+            }
+            ParsedStatement::IncrementalVacuum(max_pages) => {
+                self.conn.table.incremental_vacuum(*max_pages)?;
+                Ok(0)
+            }
+            ParsedStatement::Vacuum => {
+                self.conn.table.vacuum()?;
+                Ok(0)
+            }
+            ParsedStatement::VacuumInto(path) => {
+                self.conn.table.vacuum_into(path)?;
+                Ok(0)
+            }
+        }
+    }
+
+    fn query_bound(&mut self, values: &[Value]) -> Result<Rows<'_>> {
+        match self.statement {
+            ParsedStatement::Select => {}
+            ParsedStatement::IntegrityCheck => {
+                let problems = self.conn.table.integrity_check()?;
+                return Ok(Rows::computed(integrity_check_rows(problems)));
+            }
+            _ => {
+                self.execute_bound(values)?;
+                return Ok(Rows::computed(Vec::new()));
+            }
+        }
+
+        let table = &mut self.conn.table;
+        table.begin_read()?;
+        // Find where the scan starts before creating the cursor that `Rows` keeps, so
+        // the lock can still be released if that fails
+        let start = table_start(&*table)
+            .map(|cursor| (cursor.page_num(), cursor.cell_num(), cursor.end_of_table()));
+        let (page_num, cell_num, end_of_table) = match start {
+            Ok(start) => start,
+            Err(e) => {
+                table.end_read()?;
+                return Err(e);
+            }
+        };
+        Ok(Rows {
+            cursor: Some(Cursor::new(table, page_num, cell_num, end_of_table)),
+            computed: Vec::new().into_iter(),
+        })
+    }
+}
+
+// The rows produced by a query, one Vec of column values per row. Iteration stops
+// after the first error. The database stays share-locked until the rows run out
+// or are dropped.
+pub struct Rows<'stmt> {
+    cursor: Option<Cursor<&'stmt mut Table>>,
+    // Rows worked out in full before the query returned, for statements that
+    // don't scan the table
+    computed: std::vec::IntoIter<Vec<Value>>,
+}
+
+impl Rows<'_> {
+    fn computed(rows: Vec<Vec<Value>>) -> Self {
+        Self {
+            cursor: None,
+            computed: rows.into_iter(),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self.cursor.take() {
+            Some(mut cursor) => cursor.table().end_read(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Rows<'_> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Result<Vec<Value>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(cursor) = self.cursor.as_mut() else {
+            return self.computed.next().map(Ok);
+        };
+        if cursor.end_of_table() {
+            return self.finish().err().map(Err);
+        }
+
+        let row = cursor.row().and_then(|row| {
+            cursor.advance()?;
+            Ok(row)
+        });
+        if row.is_err() {
+            let _ = self.finish();
+        }
+        Some(row.map(|row| row.values()))
+    }
+}
+
+impl<'stmt> Rows<'stmt> {
+    // Converts each row to a `T` whose fields are matched to columns by name
+    pub fn deserialize<T: DeserializeOwned>(self) -> DeserializeRows<'stmt, T> {
+        DeserializeRows {
+            rows: self,
+            marker: PhantomData,
+        }
+    }
+}
+
+pub struct DeserializeRows<'stmt, T> {
+    rows: Rows<'stmt>,
+    marker: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Iterator for DeserializeRows<'_, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let values = self.rows.next()?;
+        Some(values.and_then(from_values))
+    }
+}
@@ -1,139 +1,208 @@
-use crate::node::internal_node::{internal_node_find_child, InternalNode};
+use crate::error::Result;
+use crate::header::StorageMode;
+use crate::node::internal_node::InternalNode;
 use crate::node::leaf_node::LeafNode;
 use crate::node::{get_node_type, NodeType};
+use crate::page::{LatchedPage, LatchedPageMut, PageMut};
 use crate::pager::Pager;
+use crate::row::{deserialize_row, Row};
 use crate::table::Table;
+use std::ops::{Deref, DerefMut};
 
-pub struct Cursor<'a> {
-    table: &'a mut Table,
+// `T` is how the cursor holds the table: a shared reference or read guard is enough
+// to read rows, so several threads can scan at once, while inserting through the
+// cursor needs `&mut Table`.
+pub struct Cursor<T> {
+    table: T,
     page_num: u32,
     cell_num: u32,
     end_of_table: bool,
 }
 
-pub fn table_start(table: &mut Table) -> Cursor {
-    let mut cursor = table_find(table, 0);
-    let node = cursor.page();
-    let node = LeafNode::new(node);
-    let num_cells = node.get_num_cells();
+pub fn table_start<T: Deref<Target = Table>>(table: T) -> Result<Cursor<T>> {
+    let mut cursor = table_find(table, 0)?;
+    let num_cells = cursor.leaf_node()?.get_num_cells();
     cursor.end_of_table = num_cells == 0;
 
-    cursor
+    Ok(cursor)
 }
 
-pub fn table_end(table: &mut Table) -> Cursor {
+pub fn table_end<T: Deref<Target = Table>>(table: T) -> Result<Cursor<T>> {
     let root_page_num = table.root_page_num();
-    let root_node = table.pager().page(root_page_num);
-    let root_node = LeafNode::new(root_node);
-    let num_cells = root_node.get_num_cells();
+    let root_node = table.pager_ref().latched_page(root_page_num)?;
+    let num_cells = LeafNode::new(root_node).get_num_cells();
 
-    Cursor {
+    Ok(Cursor {
         table,
         page_num: root_page_num,
         cell_num: num_cells,
         end_of_table: true,
-    }
+    })
 }
-pub fn table_find(table: &mut Table, key: u32) -> Cursor {
+pub fn table_find<T: Deref<Target = Table>>(table: T, key: u32) -> Result<Cursor<T>> {
     let root_page_num = table.root_page_num();
-    let root_node = table.pager().page(root_page_num);
-
-    unsafe {
-        return if get_node_type(root_node) == NodeType::Leaf {
-            leaf_node_find(table, root_page_num, key)
-        } else {
-            internal_node_find(table, root_page_num, key)
-        };
+    let root_node = table.pager_ref().latched_page(root_page_num)?;
+    let root_type = get_node_type(&root_node)?;
+    drop(root_node);
+
+    if root_type == NodeType::Leaf {
+        leaf_node_find(table, root_page_num, key)
+    } else {
+        internal_node_find(table, root_page_num, key)
     }
 }
 
-pub unsafe fn leaf_node_find(table: &mut Table, page_num: u32, key: u32) -> Cursor {
-    let node = table.pager().page(page_num);
-    let node = LeafNode::new(node);
-    let num_cells = node.get_num_cells();
-
-    // Binary search
-    let mut min_index = 0u32;
-    let mut one_past_max_index = num_cells;
-
-    while one_past_max_index != min_index {
-        let index = (min_index + one_past_max_index) / 2;
-        let key_at_index = node.get_key(index);
-
-        if key == key_at_index {
-            return Cursor {
-                table,
-                page_num,
-                cell_num: index,
-                end_of_table: false,
-            };
-        }
-
-        if key < key_at_index {
-            one_past_max_index = index;
-        } else {
-            min_index = index + 1;
-        }
-    }
+pub fn leaf_node_find<T: Deref<Target = Table>>(
+    table: T,
+    page_num: u32,
+    key: u32,
+) -> Result<Cursor<T>> {
+    let node = table.pager_ref().latched_page(page_num)?;
+    let cell_num = LeafNode::new(node).find_cell(key);
 
-    Cursor {
+    Ok(Cursor {
         table,
         page_num,
-        cell_num: min_index,
+        cell_num,
         end_of_table: false,
-    }
+    })
 }
 
-unsafe fn internal_node_find(table: &mut Table, page_number: u32, key: u32) -> Cursor {
-    let node = table.pager().page(page_number);
+fn internal_node_find<T: Deref<Target = Table>>(
+    table: T,
+    page_number: u32,
+    key: u32,
+) -> Result<Cursor<T>> {
+    let node = table.pager_ref().latched_page(page_number)?;
     let node = InternalNode::new(node);
-    let child_index = internal_node_find_child(&node, key);
-    let child_num = node.get_child(child_index);
-    let child = table.pager().page(child_num);
-    match get_node_type(child) {
+    let child_index = node.find_child(key);
+    let child_num = node.get_child(child_index)?;
+    drop(node);
+
+    let child = table.pager_ref().latched_page(child_num)?;
+    let child_type = get_node_type(&child)?;
+    drop(child);
+    match child_type {
         NodeType::Internal => internal_node_find(table, child_num, key),
         NodeType::Leaf => leaf_node_find(table, child_num, key),
     }
 }
 
-impl Cursor<'_> {
-    pub fn advance(&mut self) {
-        let node = self.page();
-        let node = LeafNode::new(node);
+// Finds the leaf `key` belongs in and latches it for writing. Each child is latched
+// before its parent is released (latch coupling), so no thread can slip in between
+// and change the path under the descent.
+pub fn latch_leaf_for_insert(
+    pager: &Pager,
+    root_page_num: u32,
+    key: u32,
+) -> Result<LeafNode<LatchedPageMut<'_>>> {
+    let mut parent = None;
+    let mut page_num = root_page_num;
+    loop {
+        let page = pager.latched_page(page_num)?;
+        if get_node_type(&page)? == NodeType::Leaf {
+            // Latches can't be upgraded, so relatch the leaf while the parent is
+            // still held
+            drop(page);
+            let leaf = pager.latched_page_mut(page_num)?;
+            drop(parent);
+            return Ok(LeafNode::new(leaf));
+        }
+
+        let node = InternalNode::new(page);
+        page_num = node.get_child(node.find_child(key))?;
+        parent = Some(node);
+    }
+}
+
+impl<T: Deref<Target = Table>> Cursor<T> {
+    // A cursor at a position found by an earlier cursor
+    pub fn new(table: T, page_num: u32, cell_num: u32, end_of_table: bool) -> Self {
+        Self {
+            table,
+            page_num,
+            cell_num,
+            end_of_table,
+        }
+    }
+
+    pub fn advance(&mut self) -> Result<()> {
+        let node = self.leaf_node()?;
+        let num_cells = node.get_num_cells();
+        let next_page_num = node.get_next_leaf();
+        let last_key = num_cells.checked_sub(1).map(|last| node.get_key(last));
+        drop(node);
+
         self.cell_num += 1;
+        if self.cell_num < num_cells {
+            return Ok(());
+        }
+        if self.table.pager_ref().storage_mode() == StorageMode::CopyOnWrite {
+            // Copy-on-write leaves aren't chained, so look up the next key instead
+            return self.seek_after(last_key);
+        }
+        if next_page_num == 0 {
+            self.end_of_table = true;
+        } else {
+            self.page_num = next_page_num;
+            self.cell_num = 0;
+        }
+        Ok(())
+    }
 
-        unsafe {
-            if self.cell_num >= node.get_num_cells() {
-                let next_page_num = node.get_next_leaf();
-                if next_page_num == 0 {
-                    self.end_of_table = true;
-                } else {
-                    self.page_num = next_page_num;
-                    self.cell_num = 0;
-                }
-            }
+    // Moves to the first row with a key above `key`
+    fn seek_after(&mut self, key: Option<u32>) -> Result<()> {
+        let Some(next_key) = key.and_then(|key| key.checked_add(1)) else {
+            self.end_of_table = true;
+            return Ok(());
+        };
+
+        let cursor = table_find(&*self.table, next_key)?;
+        let (page_num, cell_num) = (cursor.page_num, cursor.cell_num);
+        let num_cells = cursor.leaf_node()?.get_num_cells();
+
+        if cell_num >= num_cells {
+            self.end_of_table = true;
+        } else {
+            self.page_num = page_num;
+            self.cell_num = cell_num;
         }
+        Ok(())
     }
 
-    pub fn value(&mut self) -> *mut u8 {
-        let page = self.page();
-        let node = LeafNode::new(page);
-        unsafe { node.value(self.cell_num) }
+    pub fn row(&self) -> Result<Row> {
+        let cell_num = self.cell_num;
+        let node = self.leaf_node()?;
+        let mut row = Row::new();
+        deserialize_row(node.value(cell_num), &mut row);
+        Ok(row)
     }
 
     pub fn end_of_table(&self) -> bool {
         self.end_of_table
     }
 
-    pub fn page(&mut self) -> *mut u8 {
-        self.table.pager().page(self.page_num)
+    pub fn page(&self) -> Result<LatchedPage<'_>> {
+        self.table.pager_ref().latched_page(self.page_num)
+    }
+    pub fn leaf_node(&self) -> Result<LeafNode<LatchedPage<'_>>> {
+        Ok(LeafNode::new(self.page()?))
+    }
+
+    pub fn page_num(&self) -> u32 {
+        self.page_num
+    }
+    pub fn cell_num(&self) -> u32 {
+        self.cell_num
     }
+}
 
-    pub fn leaf_node(&mut self) -> LeafNode {
-        LeafNode::new(self.page())
+impl<T: DerefMut<Target = Table>> Cursor<T> {
+    pub fn page_mut(&mut self) -> Result<PageMut<'_>> {
+        self.table.pager().page_mut(self.page_num)
     }
-    pub fn internal_node(&mut self) -> InternalNode {
-        InternalNode::new(self.page())
+    pub fn leaf_node_mut(&mut self) -> Result<LeafNode<PageMut<'_>>> {
+        Ok(LeafNode::new(self.page_mut()?))
     }
 
     pub fn table(&mut self) -> &mut Table {
@@ -142,8 +211,4 @@ impl Cursor<'_> {
     pub fn pager(&mut self) -> &mut Pager {
         self.table.pager()
     }
-
-    pub fn cell_num(&self) -> u32 {
-        self.cell_num
-    }
 }
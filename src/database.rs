@@ -0,0 +1,289 @@
+use crate::error::{DbError, Result};
+use crate::header::{DbHeader, StorageMode, HEADER_PAGE_NUM};
+use crate::node::internal_node::InternalNode;
+use crate::node::leaf_node::LeafNode;
+use crate::node::{get_node_type, NodeType};
+use crate::pager::{Synchronous, DEFAULT_PAGE_SIZE};
+use crate::row::{deserialize_row, Row};
+use crate::snapshot::{Snapshot, Versions};
+use crate::statement::{
+    bind_row, integrity_check_rows, prepare_statement, Statement as ParsedStatement,
+};
+use crate::table::Table;
+use crate::value::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+// An open database that can be cloned and shared between threads. Each query
+// reads a snapshot of the pages as of the last commit, so it runs alongside
+// inserts without waiting for them or seeing them part way through. Inserts into
+// different leaves run in parallel, while one that has to split a node runs alone.
+// Every insert is committed as soon as it is made.
+// Changes are written back once the last handle is closed or dropped.
+#[derive(Clone)]
+pub struct Database {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    table: RwLock<Table>,
+    versions: Arc<Versions>,
+    readers: Mutex<Readers>,
+    // Whether the file is locked for writing, which lasts until it is closed
+    writing: AtomicBool,
+}
+
+// Queries running. The first to start takes the file's shared lock, unless the
+// database is writing, and the last to finish releases it if it was taken.
+#[derive(Default)]
+struct Readers {
+    count: usize,
+    locked: bool,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let table = self.table.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let _ = table.db_close();
+    }
+}
+
+impl Database {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_with_options(path, DEFAULT_PAGE_SIZE, StorageMode::InPlace, false)
+    }
+
+    // `page_size` and `storage_mode` are only used when creating a new database file
+    pub fn open_with_options(
+        path: &str,
+        page_size: u32,
+        storage_mode: StorageMode,
+        read_only: bool,
+    ) -> Result<Self> {
+        let mut table = Table::new();
+        table.db_open(path, page_size, storage_mode, read_only)?;
+        table.pager().commit();
+        Ok(Self {
+            shared: Arc::new(Shared {
+                versions: table.pager().versions(),
+                table: RwLock::new(table),
+                readers: Mutex::default(),
+                writing: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    // Closes the database if this is the last handle to it
+    pub fn close(self) -> Result<()> {
+        match Arc::try_unwrap(self.shared) {
+            Ok(shared) => shared.write().db_close(),
+            Err(_) => Ok(()),
+        }
+    }
+
+    // How long to wait for another process's lock before giving up with
+    // `DbError::DatabaseBusy`
+    pub fn set_busy_timeout(&self, timeout: Duration) {
+        self.shared.write().pager().set_busy_timeout(timeout);
+    }
+
+    // How long closing waits for the disk. See `Synchronous` for what each level
+    // guarantees.
+    pub fn set_synchronous(&self, synchronous: Synchronous) {
+        self.shared.write().pager().set_synchronous(synchronous);
+    }
+
+    // Runs a statement and returns the number of rows it changed
+    pub fn execute(&self, sql: &str, params: &[Value]) -> Result<usize> {
+        let (statement, parameters) = prepare_statement(sql)?;
+        let values = parameters.bind(params)?;
+        match statement {
+            ParsedStatement::Insert(operands) => {
+                let row = bind_row(&operands, &values)?;
+                self.insert(row)?;
+                Ok(1)
+            }
+            ParsedStatement::Select | ParsedStatement::IntegrityCheck => Ok(0),
+            ParsedStatement::Savepoint(name) => {
+                self.shared.write().savepoint(&name);
+                Ok(0)
+            }
+            ParsedStatement::Release(name) => {
+                self.shared.write().release(&name)?;
+                Ok(0)
+            }
+            ParsedStatement::RollbackTo(name) => {
+                let mut table = self.shared.write();
+                table.rollback_to(&name)?;
+                table.pager().commit();
+                Ok(0)
+            }
+            ParsedStatement::AutoVacuum(auto_vacuum) => {
+                self.write(|table| table.set_auto_vacuum(auto_vacuum))?;
+                Ok(0)
+            }
+            ParsedStatement::IncrementalVacuum(max_pages) => {
+                self.write(|table| table.incremental_vacuum(max_pages))?;
+                Ok(0)
+            }
+            ParsedStatement::Vacuum => {
+                let mut table = self.shared.write();
+                let result = table.vacuum();
+                table.pager().commit();
+                // Replacing the file gave up the write lock, and with it the changes
+                // that now live in the new file
+                if !table.pager().is_writing() {
+                    self.shared.writing.store(false, Ordering::Release);
+                }
+                result.map(|()| 0)
+            }
+            ParsedStatement::VacuumInto(path) => {
+                self.shared.write().vacuum_into(&path)?;
+                Ok(0)
+            }
+        }
+    }
+
+    // Runs a statement and returns every row it produces. Statements that don't
+    // produce rows are executed and return no rows.
+    pub fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Vec<Value>>> {
+        let (statement, parameters) = prepare_statement(sql)?;
+        match statement {
+            ParsedStatement::Select => {}
+            ParsedStatement::IntegrityCheck => {
+                let problems = self.shared.write().integrity_check()?;
+                return Ok(integrity_check_rows(problems));
+            }
+            _ => {
+                self.execute(sql, params)?;
+                return Ok(Vec::new());
+            }
+        }
+        parameters.bind(params)?;
+
+        self.begin_read()?;
+        let rows = select(&self.shared.versions.latest());
+        self.end_read()?;
+        rows
+    }
+
+    fn insert(&self, row: Row) -> Result<()> {
+        if !self.shared.writing.load(Ordering::Acquire) {
+            let mut table = self.shared.write();
+            table.begin_write()?;
+            table.pager().commit();
+            self.shared.writing.store(true, Ordering::Release);
+        }
+
+        // Most inserts only change one leaf, so try that alongside other inserts and
+        // queries first
+        if self.shared.read().try_insert_in_leaf(&row)? {
+            return Ok(());
+        }
+        // The leaf is full. Splitting it changes its ancestors too, so start again
+        // with the whole tree to ourselves.
+        let mut table = self.shared.write();
+        let result = table.insert(row);
+        table.pager().commit();
+        result
+    }
+
+    // Runs a change that needs the whole tree, then commits it. Changing anything
+    // locks the file for writing until it is closed.
+    fn write(&self, f: impl FnOnce(&mut Table) -> Result<()>) -> Result<()> {
+        let mut table = self.shared.write();
+        let result = f(&mut table);
+        table.pager().commit();
+        if table.pager().is_writing() {
+            self.shared.writing.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    fn begin_read(&self) -> Result<()> {
+        let mut readers = self
+            .shared
+            .readers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // While this database is writing, no other connection can change the file,
+        // so there is nothing to lock or reload
+        if readers.count == 0 && !self.shared.writing.load(Ordering::Acquire) {
+            let mut table = self.shared.write();
+            table.begin_read()?;
+            table.pager().commit();
+            readers.locked = true;
+        }
+        readers.count += 1;
+        Ok(())
+    }
+
+    fn end_read(&self) -> Result<()> {
+        let mut readers = self
+            .shared
+            .readers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        readers.count -= 1;
+        if readers.count == 0 && readers.locked {
+            readers.locked = false;
+            self.shared.write().end_read()?;
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn read(&self) -> RwLockReadGuard<'_, Table> {
+        self.table.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Table> {
+        self.table.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Reads every row in the snapshot. Each leaf is found from the root, since a
+// copy-on-write tree doesn't keep its leaves chained.
+fn select(snapshot: &Snapshot) -> Result<Vec<Vec<Value>>> {
+    let header = DbHeader::read(&snapshot.page(HEADER_PAGE_NUM)?).ok_or(DbError::Corrupt {
+        page: HEADER_PAGE_NUM,
+    })?;
+
+    let mut rows = Vec::new();
+    let mut key = 0;
+    loop {
+        let leaf = LeafNode::new(snapshot.page(find_leaf(snapshot, header.root_page_num, key)?)?);
+        let num_cells = leaf.get_num_cells();
+        let first_cell = leaf.find_cell(key);
+        if first_cell == num_cells {
+            return Ok(rows);
+        }
+        for cell_num in first_cell..num_cells {
+            let mut row = Row::new();
+            deserialize_row(leaf.value(cell_num), &mut row);
+            rows.push(row.values());
+        }
+
+        match leaf.get_key(num_cells - 1).checked_add(1) {
+            Some(next_key) => key = next_key,
+            None => return Ok(rows),
+        }
+    }
+}
+
+// The leaf `key` is in, or would be inserted into
+fn find_leaf(snapshot: &Snapshot, root_page_num: u32, key: u32) -> Result<u32> {
+    let mut page_num = root_page_num;
+    loop {
+        let page = snapshot.page(page_num)?;
+        match get_node_type(&page)? {
+            NodeType::Leaf => return Ok(page_num),
+            NodeType::Internal => {
+                let node = InternalNode::new(page);
+                page_num = node.get_child(node.find_child(key))?;
+            }
+        }
+    }
+}
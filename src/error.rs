@@ -0,0 +1,148 @@
+use crate::pager::{MAX_PAGE_SIZE, MIN_PAGE_SIZE};
+use std::fmt::{Display, Formatter};
+
+pub type Result<T> = std::result::Result<T, DbError>;
+
+#[derive(Debug)]
+pub enum DbError {
+    Io(std::io::Error),
+    NotADatabase,
+    OlderVersion,
+    InvalidPageSize,
+    InvalidFillFactor,
+    Corrupt { page: u32 },
+    Full,
+    ReadOnly,
+    DatabaseBusy,
+    NoSuchSavepoint(String),
+    VacuumInSavepoint,
+    Constraint(ConstraintError),
+    Syntax(SyntaxError),
+    Bind(BindError),
+    Serde(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConstraintError {
+    DuplicateKey,
+    KeysNotSorted,
+    TableNotEmpty,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SyntaxError {
+    UnrecognizedKeyword(String),
+    CouldNotParse,
+    StringTooLong,
+    NegativeId,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BindError {
+    ParameterCount { expected: usize, given: usize },
+    UnknownName(String),
+    Unbound(usize),
+    Mismatch { column: &'static str },
+}
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Io(e) => write!(f, "Error: {}", e),
+            DbError::NotADatabase => write!(f, "File is not a database."),
+            DbError::OlderVersion => write!(
+                f,
+                "File was created by an older version; export its rows with that version and re-import them."
+            ),
+            DbError::InvalidPageSize => write!(
+                f,
+                "Page size must be a power of two between {} and {}.",
+                MIN_PAGE_SIZE, MAX_PAGE_SIZE
+            ),
+            DbError::InvalidFillFactor => {
+                write!(f, "Error: Fill factor must be above 0 and at most 1.")
+            }
+            DbError::Corrupt { page } => write!(f, "Error: Page {} is corrupt.", page),
+            DbError::Full => write!(f, "Error: Table full."),
+            DbError::DatabaseBusy => write!(f, "Error: Database is busy."),
+            DbError::NoSuchSavepoint(name) => write!(f, "Error: No such savepoint: {}.", name),
+            DbError::VacuumInSavepoint => {
+                write!(f, "Error: Cannot vacuum while a savepoint is set.")
+            }
+            DbError::ReadOnly => write!(f, "Error: Attempt to write a read-only database."),
+            DbError::Constraint(e) => write!(f, "{}", e),
+            DbError::Syntax(e) => write!(f, "{}", e),
+            DbError::Bind(e) => write!(f, "{}", e),
+            DbError::Serde(msg) => write!(f, "Error: {}.", msg),
+        }
+    }
+}
+impl Display for ConstraintError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            ConstraintError::DuplicateKey => "Error: Duplicated key.",
+            ConstraintError::KeysNotSorted => "Error: Keys are not sorted.",
+            ConstraintError::TableNotEmpty => "Error: Table is not empty.",
+        };
+        write!(f, "{}", msg)
+    }
+}
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyntaxError::UnrecognizedKeyword(input) => {
+                write!(f, "Unrecognized keyword at start of '{}'", input)
+            }
+            SyntaxError::CouldNotParse => write!(f, "Syntax error. Could not parse statement"),
+            SyntaxError::StringTooLong => write!(f, "String is too long."),
+            SyntaxError::NegativeId => write!(f, "ID must be positive."),
+        }
+    }
+}
+
+impl Display for BindError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindError::ParameterCount { expected, given } => write!(
+                f,
+                "Error: Expected {} parameters but got {}.",
+                expected, given
+            ),
+            BindError::UnknownName(name) => write!(f, "Error: No parameter named '{}'.", name),
+            BindError::Unbound(index) => write!(f, "Error: Parameter {} is not bound.", index),
+            BindError::Mismatch { column } => {
+                write!(f, "Error: Datatype mismatch for column {}.", column)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+impl From<ConstraintError> for DbError {
+    fn from(e: ConstraintError) -> Self {
+        DbError::Constraint(e)
+    }
+}
+impl From<SyntaxError> for DbError {
+    fn from(e: SyntaxError) -> Self {
+        DbError::Syntax(e)
+    }
+}
+impl From<BindError> for DbError {
+    fn from(e: BindError) -> Self {
+        DbError::Bind(e)
+    }
+}
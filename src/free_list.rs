@@ -0,0 +1,117 @@
+use crate::error::{DbError, Result};
+use crate::header::{DbHeader, FREE_LIST_OFFSET, HEADER_PAGE_NUM};
+use crate::page::{read_u32, write_u32};
+use crate::pager::TABLE_MAX_PAGES;
+
+const FREE_LIST_ENTRY_SIZE: usize = std::mem::size_of::<u32>();
+
+// Pages a copy-on-write tree has replaced, kept to be handed out again
+#[derive(Default, Clone)]
+pub struct FreeList {
+    // Free pages that can be handed out now
+    reusable: Vec<u32>,
+    // Pages still used by the tree in the file. They only become reusable once the
+    // header has been switched to a root that no longer reaches them.
+    pending: Vec<u32>,
+    // Pages handed out since the file was last written, which its tree doesn't use
+    fresh: Vec<u32>,
+}
+
+impl FreeList {
+    // The list stored after the header on page 0
+    pub fn read(header_page: &[u8], header: &DbHeader) -> Result<Self> {
+        let corrupt = DbError::Corrupt {
+            page: HEADER_PAGE_NUM,
+        };
+        let count = header.free_page_count as usize;
+        if FREE_LIST_OFFSET + count * FREE_LIST_ENTRY_SIZE > header_page.len() {
+            return Err(corrupt);
+        }
+
+        let mut reusable = Vec::with_capacity(count);
+        for i in 0..count {
+            let page_num = read_u32(header_page, entry_offset(i));
+            if page_num == HEADER_PAGE_NUM || page_num >= TABLE_MAX_PAGES {
+                return Err(corrupt);
+            }
+            reusable.push(page_num);
+        }
+        Ok(Self {
+            reusable,
+            ..Self::default()
+        })
+    }
+
+    // Every page that will be free once the header is next written
+    pub fn pages(&self) -> Vec<u32> {
+        self.reusable.iter().chain(&self.pending).copied().collect()
+    }
+
+    // The lowest free page that the tree in the file doesn't use, if there is one.
+    // Filling the file from the start leaves the free pages at its end, where
+    // auto-vacuum can cut them off.
+    pub fn pop(&mut self) -> Option<u32> {
+        let (i, _) = self
+            .reusable
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &page_num)| page_num)?;
+        let page_num = self.reusable.swap_remove(i);
+        self.fresh.push(page_num);
+        Some(page_num)
+    }
+
+    // How many pages `pop` could hand out below `page_num`
+    pub fn reusable_below(&self, page_num: u32) -> usize {
+        self.reusable
+            .iter()
+            .filter(|&&free| free < page_num)
+            .count()
+    }
+
+    // Records a page taken from the end of the file
+    pub fn add_fresh(&mut self, page_num: u32) {
+        self.fresh.push(page_num);
+    }
+
+    pub fn free(&mut self, page_num: u32) {
+        if let Some(i) = self.fresh.iter().position(|&fresh| fresh == page_num) {
+            // Never written to the file, so nothing can still be reading it
+            self.fresh.swap_remove(i);
+            self.reusable.push(page_num);
+        } else {
+            self.pending.push(page_num);
+        }
+    }
+
+    // The tree in the file has been replaced, so nothing it used needs keeping
+    pub fn committed(&mut self) {
+        self.reusable.append(&mut self.pending);
+        self.fresh.clear();
+    }
+
+    // The file has been cut short to `num_pages`
+    pub fn truncate(&mut self, num_pages: u32) {
+        self.reusable.retain(|&page_num| page_num < num_pages);
+    }
+}
+
+// How many pages the file needs once the free pages at its end are cut off
+pub fn truncated_num_pages(num_pages: u32, free_pages: &[u32]) -> u32 {
+    let mut truncated = num_pages;
+    while truncated > 1 && free_pages.contains(&(truncated - 1)) {
+        truncated -= 1;
+    }
+    truncated
+}
+
+// Stores `pages` after the header on page 0. The header records how many there are.
+pub fn write_free_list(header_page: &mut [u8], pages: &[u32]) {
+    for (i, &page_num) in pages.iter().enumerate() {
+        write_u32(header_page, entry_offset(i), page_num);
+    }
+}
+
+fn entry_offset(i: usize) -> usize {
+    FREE_LIST_OFFSET + i * FREE_LIST_ENTRY_SIZE
+}
@@ -0,0 +1,166 @@
+// Page 0 of every database file holds the file header instead of a node
+
+use crate::node_layout::{IS_ROOT_OFFSET, NODE_TYPE_OFFSET};
+use crate::page::{read_u32, write_u32};
+use crate::table::ROOT_PAGE_NUM;
+
+pub const HEADER_PAGE_NUM: u32 = 0;
+
+const MAGIC: &[u8; 16] = b"my_sqlite db v1\0";
+const MAGIC_OFFSET: usize = 0;
+const PAGE_SIZE_OFFSET: usize = MAGIC_OFFSET + MAGIC.len();
+const PAGE_SIZE_SIZE: usize = std::mem::size_of::<u32>();
+const CHANGE_COUNTER_OFFSET: usize = PAGE_SIZE_OFFSET + PAGE_SIZE_SIZE;
+const CHANGE_COUNTER_SIZE: usize = std::mem::size_of::<u32>();
+const ROOT_PAGE_OFFSET: usize = CHANGE_COUNTER_OFFSET + CHANGE_COUNTER_SIZE;
+const ROOT_PAGE_SIZE: usize = std::mem::size_of::<u32>();
+const FREE_PAGE_COUNT_OFFSET: usize = ROOT_PAGE_OFFSET + ROOT_PAGE_SIZE;
+const FREE_PAGE_COUNT_SIZE: usize = std::mem::size_of::<u32>();
+const STORAGE_MODE_OFFSET: usize = FREE_PAGE_COUNT_OFFSET + FREE_PAGE_COUNT_SIZE;
+const STORAGE_MODE_SIZE: usize = std::mem::size_of::<u32>();
+const CHECKSUMS_OFFSET: usize = STORAGE_MODE_OFFSET + STORAGE_MODE_SIZE;
+const CHECKSUMS_SIZE: usize = std::mem::size_of::<u32>();
+const AUTO_VACUUM_OFFSET: usize = CHECKSUMS_OFFSET + CHECKSUMS_SIZE;
+const AUTO_VACUUM_SIZE: usize = std::mem::size_of::<u32>();
+pub const HEADER_SIZE: usize = AUTO_VACUUM_OFFSET + AUTO_VACUUM_SIZE;
+// The free list follows the header, one page number per free page. Even the
+// smallest page has room for every page a database can have.
+pub const FREE_LIST_OFFSET: usize = HEADER_SIZE;
+// Files from before there was a header are whole 4096-byte pages with the root
+// node on page 0
+const BASELINE_PAGE_SIZE: u64 = 4096;
+
+// How changes to the tree reach the file. Chosen when the file is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageMode {
+    // Nodes are changed where they are
+    #[default]
+    InPlace,
+    // Changed nodes are written to new pages up to a new root, and switching the
+    // header to that root is what commits them. Until then the file still holds
+    // the old tree whole, so a crash part way through writing loses nothing.
+    CopyOnWrite,
+}
+
+// Whether free pages at the end of the file are cut off. Only copy-on-write trees
+// free pages, so in-place files never shrink this way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AutoVacuum {
+    // Free pages stay in the file to be reused
+    #[default]
+    None,
+    // Every time changes are written back, pages are moved from the end of the file
+    // into free pages nearer the start, and the free pages left at the end are cut off
+    Full,
+    // Pages are only moved by `pragma incremental_vacuum`, and the free pages left at
+    // the end are cut off when changes are next written back
+    Incremental,
+}
+impl AutoVacuum {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Some(AutoVacuum::None),
+            "full" => Some(AutoVacuum::Full),
+            "incremental" => Some(AutoVacuum::Incremental),
+            _ => None,
+        }
+    }
+}
+
+pub struct DbHeader {
+    pub page_size: u32,
+    // Bumped every time a connection writes its changes back, so other connections
+    // can tell whether their cached pages are still current
+    pub change_counter: u32,
+    pub root_page_num: u32,
+    pub free_page_count: u32,
+    pub storage_mode: StorageMode,
+    // Whether every page ends in a checksum. Files from before checksums don't have
+    // them, and their pages are read as they are.
+    pub checksums: bool,
+    pub auto_vacuum: AutoVacuum,
+}
+impl DbHeader {
+    pub fn new(page_size: u32, storage_mode: StorageMode) -> Self {
+        Self {
+            page_size,
+            change_counter: 0,
+            root_page_num: ROOT_PAGE_NUM,
+            free_page_count: 0,
+            storage_mode,
+            checksums: true,
+            auto_vacuum: AutoVacuum::None,
+        }
+    }
+
+    // Whether a file without the magic string was written before there was a header,
+    // so it can be told apart from one that isn't a database at all
+    pub fn is_baseline(src: &[u8], file_length: u64) -> bool {
+        file_length > 0
+            && file_length.is_multiple_of(BASELINE_PAGE_SIZE)
+            && matches!(src[NODE_TYPE_OFFSET], 0 | 1)
+            && src[IS_ROOT_OFFSET] == 1
+    }
+
+    // None if the bytes don't start with the magic string
+    pub fn read(src: &[u8]) -> Option<Self> {
+        if &src[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()] != MAGIC {
+            return None;
+        }
+
+        let page_size = read_u32(src, PAGE_SIZE_OFFSET);
+        let change_counter = read_u32(src, CHANGE_COUNTER_OFFSET);
+        // Files from before the root could move have zeroes here
+        let root_page_num = match read_u32(src, ROOT_PAGE_OFFSET) {
+            0 => ROOT_PAGE_NUM,
+            root_page_num => root_page_num,
+        };
+        let free_page_count = read_u32(src, FREE_PAGE_COUNT_OFFSET);
+        let storage_mode = match read_u32(src, STORAGE_MODE_OFFSET) {
+            0 => StorageMode::InPlace,
+            1 => StorageMode::CopyOnWrite,
+            _ => return None,
+        };
+        let checksums = match read_u32(src, CHECKSUMS_OFFSET) {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        // Files from before auto-vacuum have zeroes here
+        let auto_vacuum = match read_u32(src, AUTO_VACUUM_OFFSET) {
+            0 => AutoVacuum::None,
+            1 => AutoVacuum::Full,
+            2 => AutoVacuum::Incremental,
+            _ => return None,
+        };
+        Some(Self {
+            page_size,
+            change_counter,
+            root_page_num,
+            free_page_count,
+            storage_mode,
+            checksums,
+            auto_vacuum,
+        })
+    }
+
+    pub fn write(&self, dest: &mut [u8]) {
+        dest[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()].copy_from_slice(MAGIC);
+        write_u32(dest, PAGE_SIZE_OFFSET, self.page_size);
+        write_u32(dest, CHANGE_COUNTER_OFFSET, self.change_counter);
+        write_u32(dest, ROOT_PAGE_OFFSET, self.root_page_num);
+        write_u32(dest, FREE_PAGE_COUNT_OFFSET, self.free_page_count);
+        let storage_mode = match self.storage_mode {
+            StorageMode::InPlace => 0,
+            StorageMode::CopyOnWrite => 1,
+        };
+        write_u32(dest, STORAGE_MODE_OFFSET, storage_mode);
+        write_u32(dest, CHECKSUMS_OFFSET, self.checksums as u32);
+        let auto_vacuum = match self.auto_vacuum {
+            AutoVacuum::None => 0,
+            AutoVacuum::Full => 1,
+            AutoVacuum::Incremental => 2,
+        };
+        write_u32(dest, AUTO_VACUUM_OFFSET, auto_vacuum);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::error::{DbError, Result};
+use crate::free_list::FreeList;
+use std::collections::HashMap;
+
+// Before-images of the pages changed since each savepoint was set, so the changes
+// made after any of them can be undone. Only the innermost savepoint records
+// pages. Releasing it hands its pages to the savepoint it was nested in.
+#[derive(Default)]
+pub struct Journal {
+    savepoints: Vec<Savepoint>,
+}
+
+struct Savepoint {
+    name: String,
+    // The pager as it was when the savepoint was set. Pages from `num_pages` on
+    // didn't exist yet, so they are dropped rather than recorded.
+    num_pages: u32,
+    free_list: FreeList,
+    pages: HashMap<u32, Box<[u8]>>,
+}
+
+// What rolling back to a savepoint puts back
+pub struct Rollback {
+    pub pages: HashMap<u32, Box<[u8]>>,
+    pub num_pages: u32,
+    pub free_list: FreeList,
+}
+
+impl Journal {
+    pub fn is_active(&self) -> bool {
+        !self.savepoints.is_empty()
+    }
+
+    pub fn savepoint(&mut self, name: &str, num_pages: u32, free_list: &FreeList) {
+        self.savepoints.push(Savepoint {
+            name: name.to_string(),
+            num_pages,
+            free_list: free_list.clone(),
+            pages: HashMap::new(),
+        });
+    }
+
+    // Whether `page_num` has to be recorded before it is changed
+    pub fn needs_before_image(&self, page_num: u32) -> bool {
+        self.savepoints.last().is_some_and(|savepoint| {
+            page_num < savepoint.num_pages && !savepoint.pages.contains_key(&page_num)
+        })
+    }
+
+    pub fn record(&mut self, page_num: u32, before_image: Box<[u8]>) {
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint.pages.insert(page_num, before_image);
+        }
+    }
+
+    // Ends the savepoint and every one set after it, keeping their changes
+    pub fn release(&mut self, name: &str) -> Result<()> {
+        let level = self.find(name)?;
+        let released = self.savepoints.split_off(level);
+        if let Some(outer) = self.savepoints.last_mut() {
+            // A page the outer savepoint hasn't recorded was unchanged until the
+            // oldest released savepoint that did record it
+            for savepoint in released {
+                for (page_num, before_image) in savepoint.pages {
+                    if page_num < outer.num_pages {
+                        outer.pages.entry(page_num).or_insert(before_image);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Ends every savepoint set after this one, and returns what undoing the changes
+    // since it was set takes. The savepoint itself stays, recording afresh.
+    pub fn rollback_to(&mut self, name: &str) -> Result<Rollback> {
+        let level = self.find(name)?;
+        let newer = self.savepoints.split_off(level + 1);
+        let savepoint = &mut self.savepoints[level];
+
+        let mut pages = std::mem::take(&mut savepoint.pages);
+        for newer_savepoint in newer {
+            for (page_num, before_image) in newer_savepoint.pages {
+                pages.entry(page_num).or_insert(before_image);
+            }
+        }
+        Ok(Rollback {
+            pages,
+            num_pages: savepoint.num_pages,
+            free_list: savepoint.free_list.clone(),
+        })
+    }
+
+    // The pager reloaded the file. Nothing can have been changed since, as that
+    // would have locked out other writers, so every savepoint starts from the file
+    // as it is now.
+    pub fn reloaded(&mut self, num_pages: u32, free_list: &FreeList) {
+        for savepoint in &mut self.savepoints {
+            savepoint.num_pages = num_pages;
+            savepoint.free_list = free_list.clone();
+            savepoint.pages.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.savepoints.clear();
+    }
+
+    // The most recent savepoint with this name
+    fn find(&self, name: &str) -> Result<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| DbError::NoSuchSavepoint(name.to_string()))
+    }
+}
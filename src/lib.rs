@@ -1,9 +1,31 @@
+#![deny(unsafe_code)]
+
+pub mod backup;
+pub mod bulk_load;
+pub mod checksum;
+pub mod connection;
 pub mod cursor;
+pub mod database;
+pub mod error;
+pub mod free_list;
+pub mod header;
+pub mod journal;
+pub mod lock;
 pub mod meta_command;
 pub mod node;
 pub mod node_layout;
+pub mod page;
 pub mod pager;
 pub mod repl;
 pub mod row;
+pub mod row_serde;
+pub mod salvage;
+pub mod snapshot;
 pub mod statement;
 pub mod table;
+pub mod value;
+
+pub use connection::{Connection, Rows, Statement};
+pub use database::Database;
+pub use error::{DbError, Result};
+pub use value::Value;
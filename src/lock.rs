@@ -0,0 +1,125 @@
+// fcntl byte-range locks are the only unsafe code in the crate
+#![allow(unsafe_code)]
+
+use crate::error::{DbError, Result};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// SQLite's lock bytes, past the end of any file this database can grow to. The
+// locks are advisory, so nothing is ever stored at these offsets.
+const PENDING_BYTE: i64 = 0x4000_0000;
+const RESERVED_BYTE: i64 = PENDING_BYTE + 1;
+const SHARED_FIRST: i64 = PENDING_BYTE + 2;
+const SHARED_SIZE: i64 = 510;
+
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// Open file description locks belong to the open file rather than the process, so
+// two connections in one process exclude each other, and closing one file doesn't
+// drop the other's locks.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SET_LOCK: libc::c_int = libc::F_OFD_SETLK;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SET_LOCK: libc::c_int = libc::F_SETLK;
+
+// Readers hold SHARED for the length of a statement. A writer takes RESERVED before
+// its first change, which readers can share but other writers can't, and
+// EXCLUSIVE to write its pages back once every reader has finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    Unlocked,
+    Shared,
+    Reserved,
+    Exclusive,
+}
+
+pub fn lock_shared(file: &File, timeout: Duration) -> Result<()> {
+    // A writer waiting for EXCLUSIVE holds PENDING, which keeps new readers out
+    wait_for(timeout, || {
+        if !set_lock(file, libc::F_RDLCK, PENDING_BYTE, 1)? {
+            return Ok(false);
+        }
+        let locked = set_lock(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE)?;
+        set_lock(file, libc::F_UNLCK, PENDING_BYTE, 1)?;
+        Ok(locked)
+    })
+}
+
+pub fn try_lock_reserved(file: &File) -> Result<bool> {
+    Ok(set_lock(file, libc::F_WRLCK, RESERVED_BYTE, 1)?)
+}
+
+pub fn lock_reserved(file: &File, timeout: Duration) -> Result<()> {
+    wait_for(timeout, || set_lock(file, libc::F_WRLCK, RESERVED_BYTE, 1))
+}
+
+pub fn lock_exclusive(file: &File, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    wait_for(timeout, || set_lock(file, libc::F_WRLCK, PENDING_BYTE, 1))?;
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let locked = wait_for(remaining, || {
+        set_lock(file, libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE)
+    });
+    if locked.is_err() {
+        // Let readers back in while the caller decides whether to retry
+        set_lock(file, libc::F_UNLCK, PENDING_BYTE, 1)?;
+    }
+    locked
+}
+
+// Gives up EXCLUSIVE but keeps RESERVED, for a writer that still has changes to
+// write back later
+pub fn downgrade_to_reserved(file: &File) -> Result<()> {
+    set_lock(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE)?;
+    set_lock(file, libc::F_UNLCK, PENDING_BYTE, 1)?;
+    Ok(())
+}
+
+pub fn unlock(file: &File) -> Result<()> {
+    // A length of 0 covers every byte from the start onwards
+    set_lock(file, libc::F_UNLCK, 0, 0)?;
+    Ok(())
+}
+
+// Retries `try_lock` until it succeeds or `timeout` runs out
+fn wait_for(timeout: Duration, mut try_lock: impl FnMut() -> io::Result<bool>) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if try_lock()? {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(DbError::DatabaseBusy);
+        }
+        sleep(BUSY_RETRY_INTERVAL.min(deadline - now));
+    }
+}
+
+// Returns false if another open file holds a conflicting lock
+fn set_lock(file: &File, kind: libc::c_int, start: i64, len: i64) -> io::Result<bool> {
+    // SAFETY: flock is plain old data, so all zeroes is a valid value
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = kind as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = start as libc::off_t;
+    lock.l_len = len as libc::off_t;
+
+    // SAFETY: the descriptor stays open for as long as `file` is borrowed, and
+    // `lock` outlives the call
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), SET_LOCK, &lock) };
+    if result == 0 {
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EAGAIN) | Some(libc::EACCES) => Ok(false),
+        _ => Err(err),
+    }
+}
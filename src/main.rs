@@ -1,14 +1,70 @@
 use libc::EXIT_FAILURE;
+use my_sqlite::header::StorageMode;
+use my_sqlite::lock::DEFAULT_BUSY_TIMEOUT;
+use my_sqlite::pager::{is_valid_page_size, Synchronous, DEFAULT_PAGE_SIZE};
 use my_sqlite::repl;
+use my_sqlite::DbError;
 use std::env;
 use std::process::exit;
+use std::time::Duration;
 
+// my_sqlite [--page-size <bytes>] [--copy-on-write] [--read-only] [--busy-timeout <ms>]
+//     [--synchronous off|normal|full] <filename>
 fn main() {
-    let argv: Vec<String> = env::args().collect();
-    if argv.len() < 2 {
+    let mut filename = None;
+    let mut page_size = DEFAULT_PAGE_SIZE;
+    let mut storage_mode = StorageMode::InPlace;
+    let mut read_only = false;
+    let mut busy_timeout = DEFAULT_BUSY_TIMEOUT;
+    let mut synchronous = Synchronous::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--page-size" => {
+                page_size = match args.next().and_then(|value| value.parse::<u32>().ok()) {
+                    Some(size) if is_valid_page_size(size) => size,
+                    _ => {
+                        println!("{}", DbError::InvalidPageSize);
+                        exit(EXIT_FAILURE);
+                    }
+                };
+            }
+            "--copy-on-write" => storage_mode = StorageMode::CopyOnWrite,
+            "--read-only" => read_only = true,
+            "--busy-timeout" => {
+                busy_timeout = match args.next().and_then(|value| value.parse::<u64>().ok()) {
+                    Some(ms) => Duration::from_millis(ms),
+                    None => {
+                        println!("Busy timeout must be a number of milliseconds.");
+                        exit(EXIT_FAILURE);
+                    }
+                };
+            }
+            "--synchronous" => {
+                synchronous = match args.next().and_then(|value| Synchronous::parse(&value)) {
+                    Some(synchronous) => synchronous,
+                    None => {
+                        println!("Synchronous must be off, normal or full.");
+                        exit(EXIT_FAILURE);
+                    }
+                };
+            }
+            _ => filename = Some(arg),
+        }
+    }
+
+    let Some(filename) = filename else {
         println!("Must supply a database filename.");
         exit(EXIT_FAILURE);
-    }
+    };
 
-    repl::start(argv[1].clone());
+    repl::start(
+        filename,
+        page_size,
+        storage_mode,
+        read_only,
+        busy_timeout,
+        synchronous,
+    );
 }
@@ -1,34 +1,82 @@
+use crate::backup::backup;
+use crate::bulk_load::DEFAULT_FILL_FACTOR;
+use crate::connection::Connection;
+use crate::error::{DbError, Result as DbResult};
 use crate::node_layout::print_constants;
+use crate::row::Row;
+use crate::statement::prepare_row;
 use crate::table::Table;
-use libc::EXIT_SUCCESS;
+use libc::{EXIT_FAILURE, EXIT_SUCCESS};
 use std::process::exit;
 
 pub enum MetaCommandResult {
     UnrecognizedCommand,
+    SyntaxError,
 }
 impl std::fmt::Debug for MetaCommandResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let display = match self {
             MetaCommandResult::UnrecognizedCommand => "Unrecognized command",
+            MetaCommandResult::SyntaxError => "Syntax error",
         };
 
         write!(f, "{}", display)
     }
 }
 
-pub fn do_meta_command(input: &str, table: &mut Table) -> Result<(), MetaCommandResult> {
-    match input {
-        ".exit" => {
-            table.db_close();
+pub fn do_meta_command(input: &str, conn: &mut Connection) -> Result<(), MetaCommandResult> {
+    let table = conn.table();
+    let args: Vec<&str> = input.split_whitespace().collect();
+    match args.as_slice() {
+        [".exit"] => {
+            match table.db_close() {
+                Ok(()) => {}
+                // Nothing was written, so stay open and let the user try again
+                Err(e @ DbError::DatabaseBusy) => {
+                    println!("{}", e);
+                    return Ok(());
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    exit(EXIT_FAILURE);
+                }
+            }
             exit(EXIT_SUCCESS);
         }
-        ".constants" => {
+        [".constants"] => {
             println!("Constants:");
-            print_constants();
+            print_constants(table.pager().layout());
         }
-        ".btree" => {
+        [".btree"] => {
             println!("Tree:");
-            table.print();
+            if let Err(e) = table.print() {
+                println!("{}", e);
+            }
+        }
+        [".check"] => match table.integrity_check() {
+            Ok(problems) if problems.is_empty() => println!("ok"),
+            Ok(problems) => {
+                for problem in problems {
+                    println!("{}", problem);
+                }
+            }
+            Err(e) => println!("{}", e),
+        },
+        [".backup", path] => {
+            if let Err(e) = backup(table, path) {
+                println!("{}", e);
+            }
+        }
+        [".stats"] => match table.space_usage() {
+            Ok(usage) => {
+                println!("Space usage:");
+                println!("{}", usage);
+            }
+            Err(e) => println!("{}", e),
+        },
+        [".import", options @ ..] => {
+            let options = ImportOptions::parse(options)?;
+            import(&options, table);
         }
         _ => {
             return Err(MetaCommandResult::UnrecognizedCommand);
@@ -37,3 +85,89 @@ pub fn do_meta_command(input: &str, table: &mut Table) -> Result<(), MetaCommand
 
     Ok(())
 }
+
+// .import [--sorted] [--fill <percent>] <file>
+struct ImportOptions<'a> {
+    filename: &'a str,
+    sorted: bool,
+    fill_factor: f64,
+}
+impl<'a> ImportOptions<'a> {
+    fn parse(args: &[&'a str]) -> Result<Self, MetaCommandResult> {
+        let mut filename = None;
+        let mut sorted = false;
+        let mut fill_factor = DEFAULT_FILL_FACTOR;
+
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            match arg {
+                "--sorted" => sorted = true,
+                "--fill" => {
+                    let percent = args
+                        .next()
+                        .and_then(|value| value.parse::<u32>().ok())
+                        .filter(|percent| (1..=100).contains(percent))
+                        .ok_or(MetaCommandResult::SyntaxError)?;
+                    fill_factor = percent as f64 / 100.0;
+                }
+                _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg),
+                _ => return Err(MetaCommandResult::SyntaxError),
+            }
+        }
+
+        Ok(Self {
+            filename: filename.ok_or(MetaCommandResult::SyntaxError)?,
+            sorted,
+            fill_factor,
+        })
+    }
+}
+
+// Each line of the file is one row: id,username,email
+fn import(options: &ImportOptions, table: &mut Table) {
+    let contents = match std::fs::read_to_string(options.filename) {
+        Ok(contents) => contents,
+        Err(_) => {
+            println!("Unable to open file '{}'", options.filename);
+            return;
+        }
+    };
+
+    let mut rows = Vec::new();
+    for (line_num, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() != 3 {
+            println!("Line {}: Syntax error. Could not parse row", line_num + 1);
+            return;
+        }
+        match prepare_row(fields[0], fields[1], fields[2]) {
+            Ok(row) => rows.push(row),
+            Err(err) => {
+                println!("Line {}: {}", line_num + 1, err);
+                return;
+            }
+        }
+    }
+
+    let num_rows = rows.len();
+    let result = if options.sorted {
+        table.bulk_load(&rows, options.fill_factor)
+    } else {
+        insert_all(rows, table)
+    };
+    match result {
+        Ok(()) => println!("Imported {} rows.", num_rows),
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn insert_all(rows: Vec<Row>, table: &mut Table) -> DbResult<()> {
+    for row in rows {
+        table.insert(row)?;
+    }
+    Ok(())
+}
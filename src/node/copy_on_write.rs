@@ -0,0 +1,260 @@
+use crate::error::{ConstraintError, Result};
+use crate::node::internal_node::InternalNode;
+use crate::node::leaf_node::LeafNode;
+use crate::node::{get_node_max_key, get_node_type, NodeTrait, NodeType};
+use crate::node_layout::{LEAF_NODE_CELL_SIZE, LEAF_NODE_KEY_OFFSET, LEAF_NODE_VALUE_OFFSET};
+use crate::page::{read_u32, write_u32};
+use crate::pager::Pager;
+use crate::row::{serialize_row, Row};
+use crate::table::Table;
+
+// A child of an internal node and the largest key under it. The key is only
+// stored for children left of the right child, so it may not be known.
+type Entry = (u32, Option<u32>);
+
+// Inserts without changing any page the tree already uses. The row's leaf and every
+// node above it are copied to new pages, splitting those that overflow, and the
+// header is switched to the new root once the whole path is built. The pages of the
+// old path go on the free list.
+//
+// Nodes in a copy-on-write tree don't keep parent pointers or a leaf chain, since
+// moving a node would mean copying every node that points at it.
+pub fn copy_on_write_insert(table: &mut Table, row: &Row) -> Result<()> {
+    let root_page_num = table.root_page_num();
+    let pager = table.pager();
+
+    // Each internal node from the root down, with the child the row belongs under
+    let mut path = Vec::new();
+    let mut rightmost = true;
+    let mut page_num = root_page_num;
+    loop {
+        let page = pager.page(page_num)?;
+        if get_node_type(&page)? == NodeType::Leaf {
+            break;
+        }
+        let node = InternalNode::new(page);
+        let child_index = node.find_child(row.id);
+        rightmost &= child_index == node.get_num_keys();
+        path.push((page_num, child_index));
+        page_num = node.get_child(child_index)?;
+    }
+    let leaf_page_num = page_num;
+
+    let mut allocated = Vec::new();
+    let new_root = match copy_path(pager, &path, leaf_page_num, rightmost, row, &mut allocated) {
+        Ok(new_root) => new_root,
+        Err(e) => {
+            // The old tree is untouched, so giving back the new pages undoes the insert
+            for page_num in allocated {
+                pager.free_page(page_num);
+            }
+            return Err(e);
+        }
+    };
+
+    pager.free_page(leaf_page_num);
+    for &(page_num, _) in &path {
+        pager.free_page(page_num);
+    }
+    table.set_root_page_num(new_root)
+}
+
+// Writes the new versions of the leaf and its ancestors, returning the new root
+fn copy_path(
+    pager: &mut Pager,
+    path: &[(u32, u32)],
+    leaf_page_num: u32,
+    rightmost: bool,
+    row: &Row,
+    allocated: &mut Vec<u32>,
+) -> Result<u32> {
+    let layout = *pager.layout();
+    let max_cells = layout.leaf_node_max_cells;
+    let max_keys = layout.internal_node_max_cells;
+
+    let leaf = LeafNode::new(pager.page(leaf_page_num)?);
+    let num_cells = leaf.get_num_cells();
+    let cell_num = leaf.find_cell(row.id);
+    if cell_num < num_cells && leaf.get_key(cell_num) == row.id {
+        return Err(ConstraintError::DuplicateKey.into());
+    }
+    let mut cells: Vec<Vec<u8>> = (0..num_cells).map(|i| leaf.cell(i).to_vec()).collect();
+
+    let mut new_cell = vec![0; LEAF_NODE_CELL_SIZE];
+    write_u32(&mut new_cell, LEAF_NODE_KEY_OFFSET, row.id);
+    serialize_row(row, &mut new_cell[LEAF_NODE_VALUE_OFFSET..]);
+    cells.insert(cell_num as usize, new_cell);
+
+    // A sequential append, split as `leaf_node_split_and_insert` explains
+    let appending = rightmost && cell_num as usize == max_cells;
+
+    // The new nodes replacing the child on the path one level down
+    let mut replacement: Vec<Entry> = if cells.len() <= max_cells {
+        vec![(write_leaf(pager, &cells, allocated)?, None)]
+    } else {
+        let left_split_count = if appending {
+            max_cells
+        } else {
+            layout.leaf_node_left_split_count
+        };
+        let (left_cells, right_cells) = cells.split_at(left_split_count);
+        let left_max = read_u32(&left_cells[left_cells.len() - 1], LEAF_NODE_KEY_OFFSET);
+        vec![
+            (write_leaf(pager, left_cells, allocated)?, Some(left_max)),
+            (write_leaf(pager, right_cells, allocated)?, None),
+        ]
+    };
+
+    for &(page_num, child_index) in path.iter().rev() {
+        let node = InternalNode::new(pager.page(page_num)?);
+        let num_keys = node.get_num_keys();
+        let mut entries = (0..num_keys)
+            .map(|i| Ok((node.get_child(i)?, Some(node.get_key(i)))))
+            .collect::<Result<Vec<Entry>>>()?;
+        entries.push((node.get_right_child(), None));
+
+        // The subtree's keys are still bounded by the one the old child had
+        let child_index = child_index as usize;
+        replacement.last_mut().unwrap().1 = entries[child_index].1;
+        entries.splice(child_index..=child_index, replacement);
+
+        replacement = if entries.len() - 1 <= max_keys {
+            vec![(write_internal_node(pager, &entries, allocated)?, None)]
+        } else {
+            let left_count = if appending {
+                entries.len() - 1
+            } else {
+                entries.len().div_ceil(2)
+            };
+            let (left, right) = entries.split_at(left_count);
+            vec![
+                (
+                    write_internal_node(pager, left, allocated)?,
+                    left[left.len() - 1].1,
+                ),
+                (write_internal_node(pager, right, allocated)?, None),
+            ]
+        };
+    }
+
+    let new_root = match replacement[..] {
+        [(page_num, _)] => page_num,
+        _ => write_internal_node(pager, &replacement, allocated)?,
+    };
+    InternalNode::new(pager.page_mut(new_root)?).set_root(true);
+    Ok(new_root)
+}
+
+fn allocate(pager: &mut Pager, allocated: &mut Vec<u32>) -> Result<u32> {
+    let page_num = pager.allocate_page()?;
+    allocated.push(page_num);
+    Ok(page_num)
+}
+
+fn write_leaf(pager: &mut Pager, cells: &[Vec<u8>], allocated: &mut Vec<u32>) -> Result<u32> {
+    let page_num = allocate(pager, allocated)?;
+    let mut leaf = LeafNode::new(pager.page_mut(page_num)?);
+    leaf.initialize();
+    leaf.set_cells(cells);
+    Ok(page_num)
+}
+
+fn write_internal_node(
+    pager: &mut Pager,
+    entries: &[Entry],
+    allocated: &mut Vec<u32>,
+) -> Result<u32> {
+    let num_keys = entries.len() - 1;
+    let mut keys = Vec::with_capacity(num_keys);
+    for &(child, key) in &entries[..num_keys] {
+        keys.push(match key {
+            Some(key) => key,
+            None => get_node_max_key(pager, child)?,
+        });
+    }
+
+    let page_num = allocate(pager, allocated)?;
+    let mut node = InternalNode::new(pager.page_mut(page_num)?);
+    node.initialize();
+    node.set_num_keys(num_keys as u32);
+    for (i, (&(child, _), key)) in entries.iter().zip(keys).enumerate() {
+        node.set_child(i as u32, child)?;
+        node.set_key(i as u32, key);
+    }
+    node.set_right_child(entries[num_keys].0);
+    Ok(page_num)
+}
+
+// Moves up to `max_pages` pages of the tree from the end of the file into free pages
+// nearer the start, so auto-vacuum can cut the end off. Returns how many were moved.
+// As with an insert, every node above a moved page is copied too, and the header is
+// switched to the new root. Stops once the last page and the nodes above it don't
+// all fit in free pages before it.
+pub fn relocate_pages(table: &mut Table, max_pages: u32) -> Result<u32> {
+    let mut moved = 0;
+    while moved < max_pages {
+        let root_page_num = table.root_page_num();
+        let pager = table.pager();
+        let free_pages = pager.free_pages();
+        let Some(last) = (1..pager.num_pages())
+            .rev()
+            .find(|page_num| !free_pages.contains(page_num))
+        else {
+            break;
+        };
+        let Some(path) = path_to(pager, root_page_num, last)? else {
+            // Not in the tree, so there is nothing to point at its new page
+            break;
+        };
+        if pager.reusable_pages_below(last) <= path.len() {
+            break;
+        }
+
+        let mut child = copy_page(pager, last)?;
+        for &(page_num, child_index) in path.iter().rev() {
+            let new_page_num = copy_page(pager, page_num)?;
+            InternalNode::new(pager.page_mut(new_page_num)?).set_child(child_index, child)?;
+            child = new_page_num;
+        }
+
+        pager.free_page(last);
+        for &(page_num, _) in &path {
+            pager.free_page(page_num);
+        }
+        table.set_root_page_num(child)?;
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+// Each internal node from the root down to `target`, with the child leading to it.
+// None if `target` can't be reached from the root.
+fn path_to(pager: &mut Pager, root_page_num: u32, target: u32) -> Result<Option<Vec<(u32, u32)>>> {
+    let mut path = Vec::new();
+    if target == root_page_num {
+        return Ok(Some(path));
+    }
+    // Only the root can be empty, so every other node has a largest key to find
+    // it by
+    let key = get_node_max_key(pager, target)?;
+    let mut page_num = root_page_num;
+    while page_num != target {
+        let page = pager.page(page_num)?;
+        if get_node_type(&page)? == NodeType::Leaf {
+            return Ok(None);
+        }
+        let node = InternalNode::new(page);
+        let child_index = node.find_child(key);
+        path.push((page_num, child_index));
+        page_num = node.get_child(child_index)?;
+    }
+    Ok(Some(path))
+}
+
+// Copies a node to the lowest free page, returning where it went
+fn copy_page(pager: &mut Pager, page_num: u32) -> Result<u32> {
+    let contents = pager.page(page_num)?.to_vec();
+    let new_page_num = pager.allocate_page()?;
+    pager.page_mut(new_page_num)?.copy_from_slice(&contents);
+    Ok(new_page_num)
+}
@@ -0,0 +1,231 @@
+use crate::error::{DbError, Result};
+use crate::header::{StorageMode, HEADER_PAGE_NUM};
+use crate::node::internal_node::InternalNode;
+use crate::node::leaf_node::LeafNode;
+use crate::node::{get_node_type, NodeTrait, NodeType};
+use crate::pager::Pager;
+use crate::table::INVALID_PAGE_NUM;
+
+// Walks the whole tree from the root and describes everything wrong with it, so an
+// empty list means the tree is sound. A page that can't be read is reported and
+// skipped, so one bad page doesn't hide problems elsewhere.
+pub fn integrity_check(pager: &mut Pager, root_page_num: u32) -> Result<Vec<String>> {
+    let mut check = Check {
+        // Copy-on-write trees keep neither parent pointers nor a leaf chain
+        linked: pager.storage_mode() == StorageMode::InPlace,
+        in_tree: vec![false; pager.num_pages() as usize],
+        complete: true,
+        leaves: Vec::new(),
+        leaf_depth: None,
+        problems: Vec::new(),
+    };
+    check.subtree(pager, root_page_num, None, (None, None), 0)?;
+    if check.linked {
+        check.leaf_chain(pager)?;
+    }
+
+    // Pages under a node that couldn't be read would all look unused
+    if !check.complete {
+        return Ok(check.problems);
+    }
+    let free_pages = pager.free_pages();
+    for page_num in 1..pager.num_pages() {
+        match (
+            check.in_tree[page_num as usize],
+            free_pages.contains(&page_num),
+        ) {
+            (true, true) => check.problem(format!("Page {}: free but in the tree", page_num)),
+            (false, false) => check.problem(format!("Page {}: never used", page_num)),
+            _ => {}
+        }
+    }
+    Ok(check.problems)
+}
+
+// What a node holds, copied out so the pager is free while its children are checked
+enum Contents {
+    Internal {
+        keys: Vec<u32>,
+        children: Vec<u32>,
+        right_child: u32,
+    },
+    Leaf {
+        keys: Vec<u32>,
+    },
+}
+
+struct Check {
+    linked: bool,
+    in_tree: Vec<bool>,
+    // Whether every node the tree points at could be read
+    complete: bool,
+    // Every leaf in key order
+    leaves: Vec<u32>,
+    leaf_depth: Option<u32>,
+    problems: Vec<String>,
+}
+
+impl Check {
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    // Checks the subtree at `page_num`, whose keys must be above the lower bound
+    // and at most the upper one. Returns the largest key in it, if it has any keys
+    // that could be read.
+    fn subtree(
+        &mut self,
+        pager: &mut Pager,
+        page_num: u32,
+        parent: Option<u32>,
+        (lower, upper): (Option<u32>, Option<u32>),
+        depth: u32,
+    ) -> Result<Option<u32>> {
+        if page_num == HEADER_PAGE_NUM || page_num >= pager.num_pages() {
+            match parent {
+                Some(parent) => self.problem(format!(
+                    "Page {}: child {} is out of range",
+                    parent, page_num
+                )),
+                None => self.problem(format!("Root page {} is out of range", page_num)),
+            }
+            self.complete = false;
+            return Ok(None);
+        }
+        if std::mem::replace(&mut self.in_tree[page_num as usize], true) {
+            self.problem(format!("Page {}: reached more than once", page_num));
+            return Ok(None);
+        }
+
+        let (contents, is_root, parent_pointer) = match read_node(pager, page_num) {
+            Ok(node) => node,
+            Err(DbError::Corrupt { page }) => {
+                self.problem(format!("Page {}: corrupt", page));
+                self.complete = false;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        if is_root != parent.is_none() {
+            let problem = if is_root {
+                "marked as the root but has a parent"
+            } else {
+                "not marked as the root"
+            };
+            self.problem(format!("Page {}: {}", page_num, problem));
+        }
+        if let (Some(parent), true) = (parent, self.linked) {
+            if parent_pointer != parent {
+                self.problem(format!(
+                    "Page {}: parent pointer is {} instead of {}",
+                    page_num, parent_pointer, parent
+                ));
+            }
+        }
+
+        match contents {
+            Contents::Leaf { keys } => {
+                self.check_keys(page_num, &keys, lower, upper);
+                match self.leaf_depth {
+                    Some(leaf_depth) if leaf_depth != depth => self.problem(format!(
+                        "Page {}: leaf at depth {} but others are at depth {}",
+                        page_num, depth, leaf_depth
+                    )),
+                    _ => self.leaf_depth = Some(depth),
+                }
+                self.leaves.push(page_num);
+                Ok(keys.last().copied())
+            }
+            Contents::Internal {
+                keys,
+                children,
+                right_child,
+            } => {
+                self.check_keys(page_num, &keys, lower, upper);
+
+                let mut child_lower = lower;
+                for (&key, &child) in keys.iter().zip(&children) {
+                    let bounds = (child_lower, Some(key));
+                    let max_key = self.subtree(pager, child, Some(page_num), bounds, depth + 1)?;
+                    if max_key.is_some_and(|max_key| max_key != key) {
+                        self.problem(format!(
+                            "Page {}: key {} is not the largest key under child {}",
+                            page_num, key, child
+                        ));
+                    }
+                    child_lower = Some(key);
+                }
+
+                if right_child == INVALID_PAGE_NUM {
+                    self.problem(format!("Page {}: no right child", page_num));
+                    self.complete = false;
+                    return Ok(None);
+                }
+                let bounds = (child_lower, upper);
+                self.subtree(pager, right_child, Some(page_num), bounds, depth + 1)
+            }
+        }
+    }
+
+    // Keys must be in ascending order and within the bounds set by the parent
+    fn check_keys(&mut self, page_num: u32, keys: &[u32], lower: Option<u32>, upper: Option<u32>) {
+        for pair in keys.windows(2) {
+            if pair[0] >= pair[1] {
+                self.problem(format!(
+                    "Page {}: key {} is out of order after {}",
+                    page_num, pair[1], pair[0]
+                ));
+            }
+        }
+        for &key in keys {
+            if lower.is_some_and(|lower| key <= lower) || upper.is_some_and(|upper| key > upper) {
+                self.problem(format!(
+                    "Page {}: key {} is outside its parent's range",
+                    page_num, key
+                ));
+            }
+        }
+    }
+
+    // Each leaf points at the next one in key order, and the last at none
+    fn leaf_chain(&mut self, pager: &mut Pager) -> Result<()> {
+        let leaves = std::mem::take(&mut self.leaves);
+        let next_leaves = leaves.iter().skip(1).copied().chain([0]);
+        for (&leaf, expected) in leaves.iter().zip(next_leaves) {
+            let next_leaf = LeafNode::new(pager.page(leaf)?).get_next_leaf();
+            if next_leaf != expected {
+                self.problem(format!(
+                    "Page {}: next leaf is {} instead of {}",
+                    leaf, next_leaf, expected
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_node(pager: &mut Pager, page_num: u32) -> Result<(Contents, bool, u32)> {
+    let page = pager.page(page_num)?;
+    let node_type = get_node_type(&page)?;
+    let contents = match node_type {
+        NodeType::Leaf => {
+            let node = LeafNode::new(page);
+            let keys = (0..node.get_num_cells()).map(|i| node.get_key(i)).collect();
+            Contents::Leaf { keys }
+        }
+        NodeType::Internal => {
+            let node = InternalNode::new(page);
+            let num_keys = node.get_num_keys();
+            Contents::Internal {
+                keys: (0..num_keys).map(|i| node.get_key(i)).collect(),
+                children: (0..num_keys)
+                    .map(|i| node.get_child(i))
+                    .collect::<Result<_>>()?,
+                right_child: node.get_right_child(),
+            }
+        }
+    };
+
+    let node = LeafNode::new(pager.page(page_num)?);
+    Ok((contents, node.is_root()?, node.get_parent()))
+}
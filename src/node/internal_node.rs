@@ -1,333 +1,225 @@
-use crate::node::{
-    create_new_root, get_node_max_key, is_node_root, node_parent, Node, NodeTrait, NodeType,
-};
+use crate::error::{DbError, Result};
+use crate::node::{create_new_root, get_node_max_key, set_parent, Node, NodeTrait, NodeType};
 use crate::node_layout::{
     INTERNAL_NODE_CELL_SIZE, INTERNAL_NODE_CHILD_SIZE, INTERNAL_NODE_HEADER_SIZE,
-    INTERNAL_NODE_MAX_CELLS, INTERNAL_NODE_NUM_KEYS_OFFSET, INTERNAL_NODE_RIGHT_CHILD_OFFSET,
+    INTERNAL_NODE_NUM_KEYS_OFFSET, INTERNAL_NODE_RIGHT_CHILD_OFFSET,
 };
-use crate::pager::Pager;
+use crate::page::{PageRead, PageWrite};
 use crate::table::{Table, INVALID_PAGE_NUM};
-use libc::{exit, EXIT_FAILURE};
 
-unsafe fn internal_node_split_and_insert(
+fn internal_node_split_and_insert(
     table: &mut Table,
     parent_page_num: u32,
     child_page_num: u32,
-) {
-    let old_page_num = parent_page_num;
-    let old_node = table.pager().page(old_page_num);
+    appending: bool,
+) -> Result<()> {
+    let old_node = Node::new(table.pager().page(parent_page_num)?);
 
-    if is_node_root(old_node) {
-        internal_node_split_and_insert_root(table, parent_page_num, child_page_num);
+    if old_node.is_root()? {
+        internal_node_split_and_insert_root(table, parent_page_num, child_page_num, appending)
     } else {
-        internal_node_split_and_insert_non_root(table, parent_page_num, child_page_num);
+        internal_node_split_and_insert_non_root(table, parent_page_num, child_page_num, appending)
     }
 }
 
-unsafe fn internal_node_split_and_insert_root(
+fn internal_node_split_and_insert_root(
     table: &mut Table,
     parent_page_num: u32,
     child_page_num: u32,
-) {
-    let mut old_page_num = parent_page_num;
-    let old = table.pager().page(old_page_num);
-    let old_node = InternalNode::new(old);
-    let old_max = old_node.get_node_max_key(table.pager());
-
-    let child = table.pager().page(child_page_num);
-    let child = InternalNode::new(child);
-    let child_max = child.get_node_max_key(table.pager());
-
-    let new_page_num = table.pager().get_unused_page_num();
-
-    create_new_root(table, new_page_num);
+    appending: bool,
+) -> Result<()> {
+    let pager = table.pager();
+    let old_max = get_node_max_key(pager, parent_page_num)?;
+    let child_max = get_node_max_key(pager, child_page_num)?;
+    let new_page_num = pager.get_unused_page_num();
+
+    create_new_root(table, new_page_num)?;
     let root_page_num = table.root_page_num();
-    let parent = table.pager().page(root_page_num);
-    let parent = InternalNode::new(parent);
-
-    old_page_num = parent.get_child(0);
-    let old_node = table.pager().page(old_page_num);
-    let old_node = InternalNode::new(old_node);
-
-    let mut cur_page_num = old_node.get_right_child();
-    let cur = table.pager().page(cur_page_num);
-    let cur = Node::new(cur);
-
-    // First put right child into new node and set right child of old node to invalid page number
-    internal_node_insert(table, new_page_num, cur_page_num);
-
-    cur.set_parent(new_page_num);
-    old_node.set_right_child(INVALID_PAGE_NUM);
-
-    // For each key until you get to the middle key, move the key and the child to the new node
-    for i in (INTERNAL_NODE_MAX_CELLS / 2 + 1..INTERNAL_NODE_MAX_CELLS).rev() {
-        cur_page_num = old_node.get_child(i as u32);
-        let cur = table.pager().page(cur_page_num);
-        let cur = Node::new(cur);
-
-        internal_node_insert(table, new_page_num, cur_page_num);
-        cur.set_parent(new_page_num);
+    let root = InternalNode::new(table.pager().page(root_page_num)?);
+    let old_page_num = root.get_child(0)?;
 
-        let old_num_keys_value = old_node.get_num_keys();
-        old_node.set_num_keys(old_num_keys_value - 1);
+    if !appending {
+        internal_node_move_upper_half(table, old_page_num, new_page_num)?;
     }
 
-    // Set child before middle key, which is now the highest key, to be node's right child and decrement number of keys
-    let old_num_keys_value = old_node.get_num_keys();
-    let new_right_child = old_node.get_child(old_num_keys_value - 1);
-
-    old_node.set_right_child(new_right_child);
-    old_node.set_num_keys(old_num_keys_value - 1);
-
     // Determine which of the two nodes after the split should contain the child to be inserted and insert the child
-    let max_after_split = old_node.get_node_max_key(table.pager());
+    let max_after_split = get_node_max_key(table.pager(), old_page_num)?;
     let destination_page_num = if child_max < max_after_split {
         old_page_num
     } else {
         new_page_num
     };
 
-    internal_node_insert(table, destination_page_num, child_page_num);
-    child.set_parent(destination_page_num);
+    internal_node_insert(table, destination_page_num, child_page_num, false)?;
+    set_parent(table.pager(), child_page_num, destination_page_num)?;
 
-    parent.update_key(old_max, old_node.get_node_max_key(table.pager()));
+    let pager = table.pager();
+    let new_max = get_node_max_key(pager, old_page_num)?;
+    InternalNode::new(pager.page_mut(root_page_num)?).update_key(old_max, new_max);
+    Ok(())
 }
 
-unsafe fn internal_node_split_and_insert_non_root(
+fn internal_node_split_and_insert_non_root(
     table: &mut Table,
     parent_page_num: u32,
     child_page_num: u32,
-) {
+    appending: bool,
+) -> Result<()> {
     let old_page_num = parent_page_num;
-    let old_node = table.pager().page(old_page_num);
-    let old_node = InternalNode::new(old_node);
-    let old_max = old_node.get_node_max_key(table.pager());
+    let pager = table.pager();
+    let old_max = get_node_max_key(pager, old_page_num)?;
+    let child_max = get_node_max_key(pager, child_page_num)?;
+    let old_node_parent = Node::new(pager.page(old_page_num)?).get_parent();
+
+    let new_page_num = pager.get_unused_page_num();
+    InternalNode::new(pager.page_mut(new_page_num)?).initialize();
+
+    if !appending {
+        internal_node_move_upper_half(table, old_page_num, new_page_num)?;
+    }
 
-    let child = table.pager().page(child_page_num);
-    let child = InternalNode::new(child);
-    let child_max = child.get_node_max_key(table.pager());
+    // Determine which of the two nodes after the split should contain the child to be inserted and insert the child
+    let max_after_split = get_node_max_key(table.pager(), old_page_num)?;
+    let destination_page_num = if child_max < max_after_split {
+        old_page_num
+    } else {
+        new_page_num
+    };
 
-    let new_page_num = table.pager().get_unused_page_num();
+    internal_node_insert(table, destination_page_num, child_page_num, false)?;
+    set_parent(table.pager(), child_page_num, destination_page_num)?;
 
-    let old_node_parent = old_node.get_parent();
-    let parent = table.pager().page(old_node_parent);
+    let pager = table.pager();
+    let new_max = get_node_max_key(pager, old_page_num)?;
+    InternalNode::new(pager.page_mut(old_node_parent)?).update_key(old_max, new_max);
 
-    let new_node = table.pager().page(new_page_num);
-    let new_node = InternalNode::new(new_node);
-    new_node.initialize();
+    // Set the parent first: if inserting into the parent splits it, the split moves
+    // new_node and updates its parent pointer.
+    set_parent(pager, new_page_num, old_node_parent)?;
+    internal_node_insert(table, old_node_parent, new_page_num, appending)
+}
 
-    let mut cur_page_num = old_node.get_right_child();
-    let mut cur = table.pager().page(cur_page_num);
+// Moves the right child and every key above the middle key of a full node into the
+// empty node at `new_page_num`. Skipped when the child was split off the end of the
+// rightmost leaf by a sequential append, which leaves the old node full and starts
+// the new node with only the inserted child.
+fn internal_node_move_upper_half(
+    table: &mut Table,
+    old_page_num: u32,
+    new_page_num: u32,
+) -> Result<()> {
+    let max_cells = table.pager().layout().internal_node_max_cells as u32;
 
     // First put right child into new node and set right child of old node to invalid page number
-    internal_node_insert(table, new_page_num, cur_page_num);
-    std::ptr::write(node_parent(cur) as *mut u32, new_page_num);
+    let mut old_node = InternalNode::new(table.pager().page_mut(old_page_num)?);
+    let right_child = old_node.get_right_child();
     old_node.set_right_child(INVALID_PAGE_NUM);
+    internal_node_insert(table, new_page_num, right_child, false)?;
+    set_parent(table.pager(), right_child, new_page_num)?;
 
     // For each key until you get to the middle key, move the key and the child to the new node
-    for i in (INTERNAL_NODE_MAX_CELLS / 2 + 1..INTERNAL_NODE_MAX_CELLS).rev() {
-        cur_page_num = old_node.get_child(i as u32);
-        cur = table.pager().page(cur_page_num);
-
-        internal_node_insert(table, new_page_num, cur_page_num);
-        std::ptr::write(node_parent(cur) as *mut u32, new_page_num);
-
-        let old_num_keys_value = old_node.get_num_keys();
-        old_node.set_num_keys(old_num_keys_value - 1);
+    for i in (max_cells / 2 + 1..max_cells).rev() {
+        let cur_page_num = InternalNode::new(table.pager().page(old_page_num)?).get_child(i)?;
+        internal_node_insert(table, new_page_num, cur_page_num, false)?;
+        set_parent(table.pager(), cur_page_num, new_page_num)?;
+
+        let mut old_node = InternalNode::new(table.pager().page_mut(old_page_num)?);
+        let old_num_keys = old_node.get_num_keys();
+        old_node.set_num_keys(old_num_keys - 1);
     }
 
     // Set child before middle key, which is now the highest key, to be node's right child and decrement number of keys
-    let old_num_keys_value = old_node.get_num_keys();
-    let new_right_child = old_node.get_child(old_num_keys_value - 1);
+    let mut old_node = InternalNode::new(table.pager().page_mut(old_page_num)?);
+    let old_num_keys = old_node.get_num_keys();
+    let new_right_child = old_node.get_child(old_num_keys - 1)?;
     old_node.set_right_child(new_right_child);
-    old_node.set_num_keys(old_num_keys_value - 1);
-
-    // Determine which of the two nodes after the split should contain the child to be inserted and insert the child
-    let max_after_split = old_node.get_node_max_key(table.pager());
-    let destination_page_num = if child_max < max_after_split {
-        old_page_num
-    } else {
-        new_page_num
-    };
-
-    internal_node_insert(table, destination_page_num, child_page_num);
-    child.set_parent(destination_page_num);
-
-    let parent = InternalNode::new(parent);
-    update_internal_node_key(&parent, old_max, old_node.get_node_max_key(table.pager()));
-
-    let old_node_parent = old_node.get_parent();
-    internal_node_insert(table, old_node_parent, new_page_num);
-    new_node.set_parent(old_node_parent);
+    old_node.set_num_keys(old_num_keys - 1);
+    Ok(())
 }
 
-pub unsafe fn internal_node_insert(table: &mut Table, parent_page_num: u32, child_page_num: u32) {
-    let parent = table.pager().page(parent_page_num);
-    let parent = InternalNode::new(parent);
-    let child = table.pager().page(child_page_num);
-    let child_max_key = get_node_max_key(table.pager(), child);
-    let index = parent.find_child(child_max_key);
+pub fn internal_node_insert(
+    table: &mut Table,
+    parent_page_num: u32,
+    child_page_num: u32,
+    appending: bool,
+) -> Result<()> {
+    let max_cells = table.pager().layout().internal_node_max_cells as u32;
+    let pager = table.pager();
+    let child_max_key = get_node_max_key(pager, child_page_num)?;
 
+    let parent = InternalNode::new(pager.page(parent_page_num)?);
+    let index = parent.find_child(child_max_key);
     let original_num_keys = parent.get_num_keys();
-    if original_num_keys >= INTERNAL_NODE_MAX_CELLS as u32 {
-        internal_node_split_and_insert(table, parent_page_num, child_page_num);
-        return;
+    let right_child_page_num = parent.get_right_child();
+
+    if original_num_keys >= max_cells {
+        return internal_node_split_and_insert(table, parent_page_num, child_page_num, appending);
     }
 
-    let right_child_page_num = parent.get_right_child();
     if right_child_page_num == INVALID_PAGE_NUM {
         // empty internal node. add to right child
-        parent.set_right_child(child_page_num);
-        return;
+        InternalNode::new(pager.page_mut(parent_page_num)?).set_right_child(child_page_num);
+        return Ok(());
     }
 
-    let right_child = table.pager().page(right_child_page_num);
+    let right_child_max_key = get_node_max_key(pager, right_child_page_num)?;
+    let mut parent = InternalNode::new(pager.page_mut(parent_page_num)?);
     parent.set_num_keys(original_num_keys + 1);
 
-    if child_max_key > get_node_max_key(table.pager(), right_child) {
+    if child_max_key > right_child_max_key {
         // Replace right child
-        parent.set_child(original_num_keys, right_child_page_num);
-        parent.set_key(
-            original_num_keys,
-            get_node_max_key(table.pager(), right_child),
-        );
+        parent.set_child(original_num_keys, right_child_page_num)?;
+        parent.set_key(original_num_keys, right_child_max_key);
         parent.set_right_child(child_page_num);
     } else {
-        // Add to new cell
-        for i in (index + 1..=original_num_keys).rev() {
-            let src = parent.cell(i - 1);
-            let dest = parent.cell(i);
-            copy_internal_cell(src, dest);
-        }
-
-        parent.set_child(index, child_page_num);
+        // Make room for the new cell
+        let start = internal_node_cell_offset(index);
+        let end = internal_node_cell_offset(original_num_keys);
+        parent
+            .page_mut()
+            .copy_within(start..end, start + INTERNAL_NODE_CELL_SIZE);
+
+        parent.set_child(index, child_page_num)?;
         parent.set_key(index, child_max_key);
     }
+    Ok(())
 }
 
-pub unsafe fn internal_node_find_child(node: &InternalNode, key: u32) -> u32 {
-    let mut min_index = 0u32;
-    let num_keys = node.get_num_keys();
-
-    let mut max_index = num_keys;
-    while min_index != max_index {
-        let index = (min_index + max_index) / 2;
-        let key_to_right = node.get_key(index);
-        if key_to_right >= key {
-            max_index = index;
-        } else {
-            min_index = index + 1;
-        }
-    }
-    min_index
-}
-
-pub unsafe fn update_internal_node_key(node: &InternalNode, old_key: u32, new_key: u32) {
-    let old_child_index = internal_node_find_child(node, old_key);
-    node.set_key(old_child_index, new_key);
-}
-
-pub unsafe fn get_internal_node_num_keys(node: *mut u8) -> u32 {
-    let node = InternalNode::new(node);
-    node.get_num_keys()
-}
-
-pub unsafe fn get_internal_node_right_child(node: *mut u8) -> u32 {
-    let node = InternalNode::new(node);
-    node.get_right_child()
+fn internal_node_cell_offset(cell_num: u32) -> usize {
+    INTERNAL_NODE_HEADER_SIZE + cell_num as usize * INTERNAL_NODE_CELL_SIZE
 }
 
-pub unsafe fn set_internal_node_right_child(node: *mut u8, child: u32) {
-    let node = InternalNode::new(node);
-    node.set_right_child(child);
+pub struct InternalNode<P> {
+    page: P,
 }
+impl<P: PageRead> NodeTrait for InternalNode<P> {
+    type Page = P;
 
-pub unsafe fn internal_node_child(node: *mut u8, child_num: u32) -> *mut u8 {
-    let node = InternalNode::new(node);
-    node.child(child_num)
-}
-
-pub unsafe fn get_internal_node_key(node: *mut u8, cell_num: u32) -> u32 {
-    let node = InternalNode::new(node);
-    node.get_key(cell_num)
-}
-
-unsafe fn copy_internal_cell(src: *mut u8, dest: *mut u8) {
-    std::ptr::copy_nonoverlapping(src, dest, INTERNAL_NODE_CELL_SIZE);
-}
-
-pub struct InternalNode {
-    data: *mut u8,
-}
-impl NodeTrait for InternalNode {
-    fn data(&self) -> *mut u8 {
-        self.data
+    fn page(&self) -> &P {
+        &self.page
     }
-
-    unsafe fn get_node_max_key(&self, pager: &mut Pager) -> u32 {
-        let right_child_page_num = self.get_right_child();
-        let right_child = pager.page(right_child_page_num);
-        get_node_max_key(pager, right_child)
+    fn page_mut(&mut self) -> &mut P {
+        &mut self.page
     }
 }
 
-impl InternalNode {
-    pub fn new(data: *mut u8) -> Self {
-        Self { data }
-    }
-    pub unsafe fn initialize(&self) {
-        self.set_node_type(NodeType::Internal);
-        self.set_root(false);
-
-        self.set_num_keys(0);
-        self.set_right_child(INVALID_PAGE_NUM);
+impl<P: PageRead> InternalNode<P> {
+    pub fn new(page: P) -> Self {
+        Self { page }
     }
 
-    pub unsafe fn get_key(&self, cell_num: u32) -> u32 {
-        std::ptr::read(self.cell(cell_num).add(INTERNAL_NODE_CHILD_SIZE) as *const u32)
+    pub fn get_key(&self, cell_num: u32) -> u32 {
+        self.page
+            .read_u32(internal_node_cell_offset(cell_num) + INTERNAL_NODE_CHILD_SIZE)
     }
-    pub unsafe fn set_key(&self, cell_num: u32, key: u32) {
-        std::ptr::write(
-            self.cell(cell_num).add(INTERNAL_NODE_CHILD_SIZE) as *mut u32,
-            key,
-        );
+    pub fn get_num_keys(&self) -> u32 {
+        self.page.read_u32(INTERNAL_NODE_NUM_KEYS_OFFSET)
     }
-
-    pub unsafe fn cell(&self, cell_num: u32) -> *mut u8 {
-        self.data
-            .add(INTERNAL_NODE_HEADER_SIZE + cell_num as usize * INTERNAL_NODE_CELL_SIZE)
+    pub fn get_right_child(&self) -> u32 {
+        self.page.read_u32(INTERNAL_NODE_RIGHT_CHILD_OFFSET)
     }
 
-    pub unsafe fn node(&self) -> *mut u8 {
-        self.data
-    }
-
-    pub unsafe fn get_num_keys(&self) -> u32 {
-        std::ptr::read(self.data.add(INTERNAL_NODE_NUM_KEYS_OFFSET) as *const u32)
-    }
-    pub unsafe fn set_num_keys(&self, num_keys: u32) {
-        std::ptr::write(
-            self.data.add(INTERNAL_NODE_NUM_KEYS_OFFSET) as *mut u32,
-            num_keys,
-        );
-    }
-
-    pub unsafe fn get_right_child(&self) -> u32 {
-        std::ptr::read(self.data.add(INTERNAL_NODE_RIGHT_CHILD_OFFSET) as *const u32)
-    }
-
-    pub unsafe fn set_right_child(&self, child: u32) {
-        std::ptr::write(
-            self.data.add(INTERNAL_NODE_RIGHT_CHILD_OFFSET) as *mut u32,
-            child,
-        );
-    }
-
-    unsafe fn right_child(&self) -> *mut u8 {
-        self.data.add(INTERNAL_NODE_RIGHT_CHILD_OFFSET)
-    }
-
-    pub unsafe fn find_child(&self, key: u32) -> u32 {
+    pub fn find_child(&self, key: u32) -> u32 {
         let num_keys = self.get_num_keys();
 
         let mut min_index = 0u32;
@@ -346,46 +238,63 @@ impl InternalNode {
         min_index
     }
 
-    pub unsafe fn child(&self, child_num: u32) -> *mut u8 {
+    // Offset of the page number of child `child_num`. `child_num == num_keys` is the right child.
+    fn child_offset(&self, child_num: u32) -> Result<usize> {
         let num_keys = self.get_num_keys();
 
         if child_num > num_keys {
-            println!(
-                "Tried to access child num {} > num_keys {}",
-                child_num, num_keys
-            );
-            exit(EXIT_FAILURE);
+            // Tried to access child num beyond num_keys
+            Err(DbError::Corrupt {
+                page: self.page_num(),
+            })
         } else if child_num == num_keys {
-            let right_child = self.get_right_child();
-            if right_child == INVALID_PAGE_NUM {
-                println!("Tried to access child of node, but was invalid page");
-                exit(EXIT_FAILURE);
-            }
-
-            self.right_child()
+            Ok(INTERNAL_NODE_RIGHT_CHILD_OFFSET)
         } else {
-            // child_num < num_keys
-            let child = self.cell(child_num);
-            if std::ptr::read(child as *const u32) == INVALID_PAGE_NUM {
-                println!(
-                    "Tried to access child {} of node, but was invalid page",
-                    child_num
-                );
-                exit(EXIT_FAILURE);
-            }
+            Ok(internal_node_cell_offset(child_num))
+        }
+    }
 
-            child
+    pub fn get_child(&self, child_num: u32) -> Result<u32> {
+        let child = self.page.read_u32(self.child_offset(child_num)?);
+        if child == INVALID_PAGE_NUM {
+            // Tried to access child of node, but was invalid page
+            return Err(DbError::Corrupt {
+                page: self.page_num(),
+            });
         }
+        Ok(child)
     }
+}
+
+impl<P: PageWrite> InternalNode<P> {
+    pub fn initialize(&mut self) {
+        self.set_node_type(NodeType::Internal);
+        self.set_root(false);
 
-    pub unsafe fn set_child(&self, cell: u32, child: u32) {
-        std::ptr::write(self.child(cell) as *mut u32, child);
+        self.set_num_keys(0);
+        self.set_right_child(INVALID_PAGE_NUM);
     }
-    pub unsafe fn get_child(&self, cell: u32) -> u32 {
-        std::ptr::read(self.child(cell) as *const u32)
+
+    pub fn set_key(&mut self, cell_num: u32, key: u32) {
+        self.page.write_u32(
+            internal_node_cell_offset(cell_num) + INTERNAL_NODE_CHILD_SIZE,
+            key,
+        );
+    }
+    pub fn set_num_keys(&mut self, num_keys: u32) {
+        self.page.write_u32(INTERNAL_NODE_NUM_KEYS_OFFSET, num_keys);
+    }
+    pub fn set_right_child(&mut self, child: u32) {
+        self.page.write_u32(INTERNAL_NODE_RIGHT_CHILD_OFFSET, child);
+    }
+
+    pub fn set_child(&mut self, child_num: u32, child: u32) -> Result<()> {
+        let offset = self.child_offset(child_num)?;
+        self.page.write_u32(offset, child);
+        Ok(())
     }
 
-    pub unsafe fn update_key(&self, old_key: u32, new_key: u32) {
+    pub fn update_key(&mut self, old_key: u32, new_key: u32) {
         let old_child_index = self.find_child(old_key);
         self.set_key(old_child_index, new_key);
     }
@@ -1,176 +1,166 @@
 use crate::cursor::Cursor;
-use crate::node::internal_node::{internal_node_insert, update_internal_node_key, InternalNode};
-use crate::node::{create_new_root, NodeTrait, NodeType};
+use crate::error::{DbError, Result};
+use crate::node::internal_node::{internal_node_insert, InternalNode};
+use crate::node::{create_new_root, get_node_max_key, split_page_count, NodeTrait, NodeType};
 use crate::node_layout::{
-    LEAF_NODE_CELL_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_KEY_SIZE, LEAF_NODE_LEFT_SPLIT_COUNT,
-    LEAF_NODE_MAX_CELLS, LEAF_NODE_NEXT_LEAF_OFFSET, LEAF_NODE_NUM_CELLS_OFFSET,
-    LEAF_NODE_RIGHT_SPLIT_COUNT,
+    LEAF_NODE_CELL_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_KEY_OFFSET, LEAF_NODE_KEY_SIZE,
+    LEAF_NODE_NEXT_LEAF_OFFSET, LEAF_NODE_NUM_CELLS_OFFSET, LEAF_NODE_VALUE_OFFSET,
+    LEAF_NODE_VALUE_SIZE,
 };
-use crate::pager::{Pager, TABLE_MAX_PAGES};
+use crate::page::{write_u32, PageRead, PageWrite};
+use crate::pager::TABLE_MAX_PAGES;
 use crate::row::{serialize_row, Row};
-use crate::statement::ExecuteResult;
+use crate::table::Table;
 
-pub fn leaf_node_insert(cursor: &mut Cursor, key: u32, value: &Row) -> ExecuteResult {
-    let node = cursor.leaf_node();
+pub fn leaf_node_insert(cursor: &mut Cursor<&mut Table>, key: u32, value: &Row) -> Result<()> {
+    let max_cells = cursor.pager().layout().leaf_node_max_cells as u32;
+    let cell_num = cursor.cell_num();
+    let mut node = cursor.leaf_node_mut()?;
 
-    unsafe {
-        let num_cells = node.get_num_cells();
-        if num_cells >= LEAF_NODE_MAX_CELLS as u32 {
-            // Node full
-            return leaf_node_split_and_insert(cursor, key, value);
-        }
-
-        if cursor.cell_num() < num_cells {
-            for i in (cursor.cell_num() + 1..=num_cells).rev() {
-                copy_leaf_cell(node.cell(i - 1), node.cell(i));
-            }
-        }
-
-        //node.set_next_leaf(num_cells);
-        node.set_num_cells(num_cells + 1);
-        node.set_key(cursor.cell_num(), key);
-
-        serialize_row(value, node.value(cursor.cell_num()));
+    let num_cells = node.get_num_cells();
+    if num_cells >= max_cells {
+        // Node full
+        return leaf_node_split_and_insert(cursor, key, value);
     }
-    ExecuteResult::Success
-}
 
-unsafe fn leaf_node_split_and_insert(cursor: &mut Cursor, key: u32, value: &Row) -> ExecuteResult {
-    let old_node = cursor.leaf_node();
-    let old_max = old_node.get_node_max_key(cursor.pager());
+    node.insert_cell(cell_num, key, value);
+    Ok(())
+}
 
-    let new_page_num = cursor.pager().get_unused_page_num();
-    if new_page_num >= TABLE_MAX_PAGES {
-        return ExecuteResult::TableFull;
-    }
+fn leaf_node_split_and_insert(
+    cursor: &mut Cursor<&mut Table>,
+    key: u32,
+    value: &Row,
+) -> Result<()> {
+    let layout = *cursor.pager().layout();
+    let max_cells = layout.leaf_node_max_cells as u32;
+    let cell_num = cursor.cell_num();
+    let old_page_num = cursor.page_num();
+    let table = cursor.table();
+
+    // The split can cascade up to the root. Check that every page it needs can be
+    // allocated before changing anything, so a full table is left intact.
+    let pages_needed = split_page_count(table, old_page_num)?;
+    if table.pager().get_unused_page_num() + pages_needed > TABLE_MAX_PAGES {
+        return Err(DbError::Full);
+    }
+
+    let pager = table.pager();
+    let old_max = get_node_max_key(pager, old_page_num)?;
+    let new_page_num = pager.get_unused_page_num();
+
+    // Gather all existing cells plus the new one in key order, then divide them
+    // between the old and new nodes.
+    let old_node = LeafNode::new(pager.page(old_page_num)?);
+    let old_is_root = old_node.is_root()?;
+    let old_parent = old_node.get_parent();
+    let old_next_leaf = old_node.get_next_leaf();
+    let mut cells: Vec<Vec<u8>> = (0..max_cells).map(|i| old_node.cell(i).to_vec()).collect();
+
+    let mut new_cell = vec![0; LEAF_NODE_CELL_SIZE];
+    write_u32(&mut new_cell, LEAF_NODE_KEY_OFFSET, key);
+    serialize_row(value, &mut new_cell[LEAF_NODE_VALUE_OFFSET..]);
+    cells.insert(cell_num as usize, new_cell);
+
+    // Appending past the end of the rightmost leaf is the sequential insert pattern.
+    // Leave the old leaf full and start the new one with just the inserted cell, so
+    // an append-only workload doesn't leave every leaf half empty.
+    let appending = cell_num == max_cells && old_next_leaf == 0;
+    let left_split_count = if appending {
+        max_cells as usize
+    } else {
+        layout.leaf_node_left_split_count
+    };
+    let (left_cells, right_cells) = cells.split_at(left_split_count);
 
-    let new_node = cursor.pager().page(new_page_num);
-    let new_node = LeafNode::new(new_node);
+    let mut new_node = LeafNode::new(pager.page_mut(new_page_num)?);
     new_node.initialize();
-    new_node.set_parent(old_node.get_parent());
-    new_node.set_next_leaf(old_node.get_next_leaf());
+    new_node.set_parent(old_parent);
+    new_node.set_next_leaf(old_next_leaf);
+    new_node.set_cells(right_cells);
 
+    let mut old_node = LeafNode::new(pager.page_mut(old_page_num)?);
     old_node.set_next_leaf(new_page_num);
+    old_node.set_cells(left_cells);
 
-    for i in (0..=LEAF_NODE_MAX_CELLS as u32).rev() {
-        let destination_node = if i >= LEAF_NODE_LEFT_SPLIT_COUNT as u32 {
-            &new_node
-        } else {
-            &old_node
-        };
-
-        let index_within_node = i % LEAF_NODE_LEFT_SPLIT_COUNT as u32;
-        // let index_within_node = if i > LEAF_NODE_LEFT_SPLIT_COUNT {
-        //     i % LEAF_NODE_RIGHT_SPLIT_COUNT
-        // } else {
-        //     i % LEAF_NODE_LEFT_SPLIT_COUNT
-        // };
-        if i == cursor.cell_num() {
-            let dest = destination_node.value(index_within_node);
-            serialize_row(value, dest);
-            destination_node.set_key(index_within_node, key);
-        } else if i > cursor.cell_num() {
-            let src = old_node.cell(i - 1);
-            let dest = destination_node.cell(index_within_node);
-            copy_leaf_cell(src, dest);
-        } else {
-            let src = old_node.cell(i);
-            let dest = destination_node.cell(index_within_node);
-            copy_leaf_cell(src, dest);
-        }
-    }
-
-    old_node.set_num_cells(LEAF_NODE_LEFT_SPLIT_COUNT as u32);
-    new_node.set_num_cells(LEAF_NODE_RIGHT_SPLIT_COUNT as u32);
-
-    if old_node.is_root() {
-        create_new_root(cursor.table(), new_page_num);
+    if old_is_root {
+        create_new_root(table, new_page_num)
     } else {
-        let parent_page_num = old_node.get_parent();
-        let new_max = old_node.get_node_max_key(cursor.pager());
-        let parent = cursor.pager().page(parent_page_num);
-        let parent = InternalNode::new(parent);
-        update_internal_node_key(&parent, old_max, new_max);
-        internal_node_insert(cursor.table(), parent_page_num, new_page_num);
+        let new_max = get_node_max_key(pager, old_page_num)?;
+        let mut parent = InternalNode::new(pager.page_mut(old_parent)?);
+        parent.update_key(old_max, new_max);
+        internal_node_insert(table, old_parent, new_page_num, appending)
     }
-
-    ExecuteResult::Success
-}
-
-pub unsafe fn get_leaf_node_num_cells(node: *mut u8) -> u32 {
-    let data = LeafNode::new(node);
-    data.get_num_cells()
 }
 
-pub unsafe fn get_leaf_node_key(node: *mut u8, cell_num: u32) -> u32 {
-    let node = LeafNode::new(node);
-    node.get_key(cell_num)
+fn leaf_node_cell_offset(cell_num: u32) -> usize {
+    LEAF_NODE_HEADER_SIZE + cell_num as usize * LEAF_NODE_CELL_SIZE
 }
 
-unsafe fn copy_leaf_cell(src: *mut u8, dest: *mut u8) {
-    std::ptr::copy_nonoverlapping(src, dest, LEAF_NODE_CELL_SIZE);
+pub struct LeafNode<P> {
+    page: P,
 }
+impl<P: PageRead> NodeTrait for LeafNode<P> {
+    type Page = P;
 
-pub struct LeafNode {
-    data: *mut u8,
-}
-impl NodeTrait for LeafNode {
-    fn data(&self) -> *mut u8 {
-        self.data
-    }
-    // unsafe fn get_node_max_key(&self, _pager: &mut Pager) -> u32 {
-    //     let num_cells = self.get_num_cells();
-    //     self.get_key(num_cells - 1)
-    // }
-}
-
-impl LeafNode {
-    pub fn new(data: *mut u8) -> Self {
-        Self { data }
+    fn page(&self) -> &P {
+        &self.page
     }
-    pub unsafe fn initialize(&self) {
-        self.set_num_cells(0);
-        self.set_next_leaf(0);
-        self.set_node_type(NodeType::Leaf);
-        self.set_root(false);
+    fn page_mut(&mut self) -> &mut P {
+        &mut self.page
     }
+}
 
-    pub unsafe fn value(&self, cell_num: u32) -> *mut u8 {
-        self.cell(cell_num).add(LEAF_NODE_KEY_SIZE)
+impl<P: PageRead> LeafNode<P> {
+    pub fn new(page: P) -> Self {
+        Self { page }
     }
 
-    pub unsafe fn next_leaf(&self) -> *mut u8 {
-        self.data.add(LEAF_NODE_NEXT_LEAF_OFFSET)
+    pub fn cell(&self, cell_num: u32) -> &[u8] {
+        let offset = leaf_node_cell_offset(cell_num);
+        &self.page[offset..offset + LEAF_NODE_CELL_SIZE]
     }
-    pub unsafe fn get_next_leaf(&self) -> u32 {
-        std::ptr::read(self.data.add(LEAF_NODE_NEXT_LEAF_OFFSET) as *const u32)
-    }
-    pub unsafe fn set_next_leaf(&self, next: u32) {
-        std::ptr::write(self.data.add(LEAF_NODE_NEXT_LEAF_OFFSET) as *mut u32, next);
+    pub fn value(&self, cell_num: u32) -> &[u8] {
+        let offset = leaf_node_cell_offset(cell_num) + LEAF_NODE_KEY_SIZE;
+        &self.page[offset..offset + LEAF_NODE_VALUE_SIZE]
     }
 
-    pub unsafe fn cell(&self, cell_num: u32) -> *mut u8 {
-        self.data
-            .add(LEAF_NODE_HEADER_SIZE + cell_num as usize * LEAF_NODE_CELL_SIZE)
+    pub fn get_next_leaf(&self) -> u32 {
+        self.page.read_u32(LEAF_NODE_NEXT_LEAF_OFFSET)
     }
     pub fn get_num_cells(&self) -> u32 {
-        unsafe { std::ptr::read(self.data.add(LEAF_NODE_NUM_CELLS_OFFSET) as *const u32) }
-    }
-    pub unsafe fn set_num_cells(&self, num_cells: u32) {
-        std::ptr::write(
-            self.data.add(LEAF_NODE_NUM_CELLS_OFFSET) as *mut u32,
-            num_cells,
-        )
+        self.page.read_u32(LEAF_NODE_NUM_CELLS_OFFSET)
     }
     pub fn get_key(&self, cell_num: u32) -> u32 {
-        unsafe { std::ptr::read(self.cell(cell_num) as *const u32) }
+        self.page
+            .read_u32(leaf_node_cell_offset(cell_num) + LEAF_NODE_KEY_OFFSET)
     }
-    pub unsafe fn set_key(&self, cell_num: u32, key: u32) {
-        std::ptr::write(self.cell(cell_num) as *mut u32, key);
+
+    // The cell holding `key`, or where it would be inserted
+    pub fn find_cell(&self, key: u32) -> u32 {
+        // Binary search
+        let mut min_index = 0u32;
+        let mut one_past_max_index = self.get_num_cells();
+
+        while one_past_max_index != min_index {
+            let index = (min_index + one_past_max_index) / 2;
+            let key_at_index = self.get_key(index);
+
+            if key == key_at_index {
+                return index;
+            }
+
+            if key < key_at_index {
+                one_past_max_index = index;
+            } else {
+                min_index = index + 1;
+            }
+        }
+        min_index
     }
 
     #[allow(dead_code)]
-    pub unsafe fn print_leaf_node(&self) {
+    pub fn print_leaf_node(&self) {
         let num_cells = self.get_num_cells();
         println!("leaf (size {})", num_cells);
         for i in 0..num_cells {
@@ -179,3 +169,53 @@ impl LeafNode {
         }
     }
 }
+
+impl<P: PageWrite> LeafNode<P> {
+    pub fn initialize(&mut self) {
+        self.set_num_cells(0);
+        self.set_next_leaf(0);
+        self.set_node_type(NodeType::Leaf);
+        self.set_root(false);
+    }
+
+    // Inserts a cell at `cell_num`, which the caller has checked there is room for
+    pub fn insert_cell(&mut self, cell_num: u32, key: u32, value: &Row) {
+        let num_cells = self.get_num_cells();
+        if cell_num < num_cells {
+            // Make room for new cell
+            let start = leaf_node_cell_offset(cell_num);
+            let end = leaf_node_cell_offset(num_cells);
+            self.page
+                .copy_within(start..end, start + LEAF_NODE_CELL_SIZE);
+        }
+
+        self.set_num_cells(num_cells + 1);
+        self.set_key(cell_num, key);
+        serialize_row(value, self.value_mut(cell_num));
+    }
+
+    pub fn value_mut(&mut self, cell_num: u32) -> &mut [u8] {
+        let offset = leaf_node_cell_offset(cell_num) + LEAF_NODE_KEY_SIZE;
+        &mut self.page[offset..offset + LEAF_NODE_VALUE_SIZE]
+    }
+
+    // Replaces the node's cells with `cells`, which must already be in key order
+    pub fn set_cells(&mut self, cells: &[Vec<u8>]) {
+        for (i, cell) in cells.iter().enumerate() {
+            let offset = leaf_node_cell_offset(i as u32);
+            self.page[offset..offset + LEAF_NODE_CELL_SIZE].copy_from_slice(cell);
+        }
+        self.set_num_cells(cells.len() as u32);
+    }
+
+    pub fn set_next_leaf(&mut self, next: u32) {
+        self.page.write_u32(LEAF_NODE_NEXT_LEAF_OFFSET, next);
+    }
+    pub fn set_num_cells(&mut self, num_cells: u32) {
+        self.page.write_u32(LEAF_NODE_NUM_CELLS_OFFSET, num_cells);
+    }
+    pub fn set_key(&mut self, cell_num: u32, key: u32) {
+        self.page
+            .write_u32(leaf_node_cell_offset(cell_num) + LEAF_NODE_KEY_OFFSET, key);
+    }
+}
@@ -1,11 +1,13 @@
-use crate::node::internal_node::{
-    get_internal_node_right_child, set_internal_node_right_child, InternalNode,
-};
-use crate::node::leaf_node::{get_leaf_node_key, get_leaf_node_num_cells, LeafNode};
+use crate::error::{DbError, Result};
+use crate::node::internal_node::InternalNode;
+use crate::node::leaf_node::LeafNode;
 use crate::node_layout::*;
-use crate::pager::{Pager, PAGE_SIZE};
-use crate::table::Table;
+use crate::page::{PageRead, PageWrite};
+use crate::pager::Pager;
+use crate::table::{Table, INVALID_PAGE_NUM};
 
+pub mod copy_on_write;
+pub mod integrity_check;
 pub mod internal_node;
 pub mod leaf_node;
 
@@ -14,12 +16,14 @@ pub enum NodeType {
     Internal,
     Leaf,
 }
-impl From<u8> for NodeType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for NodeType {
+    type Error = ();
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
         match value {
-            0 => NodeType::Internal,
-            1 => NodeType::Leaf,
-            _ => panic!("Invalid value for NodeType"),
+            0 => Ok(NodeType::Internal),
+            1 => Ok(NodeType::Leaf),
+            _ => Err(()),
         }
     }
 }
@@ -32,60 +36,102 @@ impl From<NodeType> for u8 {
     }
 }
 
-unsafe fn create_new_root(table: &mut Table, right_child_page_number: u32) {
+fn create_new_root(table: &mut Table, right_child_page_number: u32) -> Result<()> {
     let root_page_num = table.root_page_num();
-    let root = table.pager().page(root_page_num);
-
-    let right_child = table.pager().page(right_child_page_number);
-    let right_child = InternalNode::new(right_child);
+    let pager = table.pager();
 
-    let left_child_page_num = table.pager().get_unused_page_num();
-    let left_child = table.pager().page(left_child_page_num);
-    let left_child = InternalNode::new(left_child);
+    let root = pager.page(root_page_num)?;
+    let root_is_internal = get_node_type(&root)? == NodeType::Internal;
+    let root_data = root.to_vec();
 
-    if get_node_type(root) == NodeType::Internal {
+    let mut right_child = InternalNode::new(pager.page_mut(right_child_page_number)?);
+    if root_is_internal {
         right_child.initialize();
-        left_child.initialize();
     }
+    right_child.set_parent(root_page_num);
 
     // Copy root data to new node(left_child)
-    copy_node(root, left_child.node());
-
-    // left_child is internal node
+    let left_child_page_num = pager.get_unused_page_num();
+    let mut left_child = InternalNode::new(pager.page_mut(left_child_page_num)?);
+    left_child.page_mut().copy_from_slice(&root_data);
     left_child.set_root(false);
+    left_child.set_parent(root_page_num);
 
-    if left_child.get_node_type() == NodeType::Internal {
+    if root_is_internal {
         let left_child_num_keys = left_child.get_num_keys();
-        for i in 0..left_child_num_keys {
-            let left_left_child = left_child.get_child(i);
-            let child = table.pager().page(left_left_child);
-            let child_node = LeafNode::new(child);
-            child_node.set_parent(left_child_page_num);
+        let children = (0..=left_child_num_keys)
+            .map(|i| left_child.get_child(i))
+            .collect::<Result<Vec<_>>>()?;
+        for child in children {
+            set_parent(pager, child, left_child_page_num)?;
         }
-        let left_right_child = left_child.get_right_child();
-        let child = table.pager().page(left_right_child);
-        let child_node = LeafNode::new(child);
-        child_node.set_parent(left_child_page_num);
     }
 
     // reset root as internal node
-    let root = InternalNode::new(root);
+    let left_child_max_key = get_node_max_key(pager, left_child_page_num)?;
+    let mut root = InternalNode::new(pager.page_mut(root_page_num)?);
     root.initialize();
     root.set_root(true);
     root.set_num_keys(1);
-    std::ptr::write(root.child(0) as *mut u32, left_child_page_num);
-    let left_child_max_key = get_node_max_key(table.pager(), left_child.node());
-    //let left_child_max_key = left_child.get_node_max_key(table.pager());
+    root.set_child(0, left_child_page_num)?;
     root.set_key(0, left_child_max_key);
-    set_internal_node_right_child(root.node(), right_child_page_number);
+    root.set_right_child(right_child_page_number);
+    Ok(())
+}
 
-    left_child.set_parent(table.root_page_num());
-    right_child.set_parent(table.root_page_num());
+// Number of new pages a split starting at `page_num` needs. Splits cascade up through
+// full parents, and splitting the root moves its contents to one more new page.
+fn split_page_count(table: &mut Table, page_num: u32) -> Result<u32> {
+    let max_keys = table.pager().layout().internal_node_max_cells as u32;
+    let pager = table.pager();
+
+    let mut pages = 1;
+    let mut page_num = page_num;
+    loop {
+        let node = Node::new(pager.page(page_num)?);
+        if node.is_root()? {
+            return Ok(pages + 1);
+        }
+
+        let parent_page_num = node.get_parent();
+        let parent = InternalNode::new(pager.page(parent_page_num)?);
+        if parent.get_num_keys() < max_keys {
+            return Ok(pages);
+        }
+        pages += 1;
+        page_num = parent_page_num;
+    }
+}
+
+pub fn get_node_type(page: &impl PageRead) -> Result<NodeType> {
+    page.read_u8(NODE_TYPE_OFFSET)
+        .try_into()
+        .map_err(|_| DbError::Corrupt { page: page.num() })
 }
 
-pub unsafe fn get_node_type(data: *mut u8) -> NodeType {
-    let value = *data.add(NODE_TYPE_OFFSET);
-    value.into()
+// A node with more cells than fit is corrupt, since reading them would run past
+// the end of the page. Pages that aren't nodes are left for their readers to check.
+pub fn check_cell_count(page: &impl PageRead, layout: &NodeLayout) -> Result<()> {
+    let (num_cells, max_cells) = match get_node_type(page) {
+        Ok(NodeType::Leaf) => (
+            page.read_u32(LEAF_NODE_NUM_CELLS_OFFSET),
+            layout.leaf_node_max_cells,
+        ),
+        Ok(NodeType::Internal) => (
+            page.read_u32(INTERNAL_NODE_NUM_KEYS_OFFSET),
+            layout.internal_node_max_cells,
+        ),
+        Err(_) => return Ok(()),
+    };
+    if num_cells as usize > max_cells {
+        return Err(DbError::Corrupt { page: page.num() });
+    }
+    Ok(())
+}
+
+fn set_parent(pager: &mut Pager, page_num: u32, parent: u32) -> Result<()> {
+    Node::new(pager.page_mut(page_num)?).set_parent(parent);
+    Ok(())
 }
 
 fn indent(level: usize) {
@@ -93,128 +139,205 @@ fn indent(level: usize) {
     print!("{}", indent);
 }
 
-pub fn print_tree(pager: &mut Pager, page_num: u32, indentation_level: usize) {
-    let node = pager.page(page_num);
+pub fn print_tree(pager: &mut Pager, page_num: u32, indentation_level: usize) -> Result<()> {
+    let node = pager.page(page_num)?;
 
-    unsafe {
-        match get_node_type(node) {
-            NodeType::Internal => {
-                let node = InternalNode::new(node);
-                let num_keys = node.get_num_keys();
-                indent(indentation_level);
-                println!("- internal (size {})", num_keys);
-
-                if num_keys > 0 {
-                    for i in 0..num_keys {
-                        let child = node.get_child(i);
-                        print_tree(pager, child, indentation_level + 1);
-
-                        indent(indentation_level + 1);
-                        println!("- key {}", node.get_key(i));
-                    }
-                    let child = node.get_right_child();
-                    print_tree(pager, child, indentation_level + 1);
-                }
+    match get_node_type(&node)? {
+        NodeType::Internal => {
+            let node = InternalNode::new(node);
+            let num_keys = node.get_num_keys();
+            indent(indentation_level);
+            println!("- internal (size {})", num_keys);
+
+            let cells = (0..num_keys)
+                .map(|i| Ok((node.get_child(i)?, node.get_key(i))))
+                .collect::<Result<Vec<_>>>()?;
+            // A node split off by a sequential append starts out with only a right child
+            let right_child = node.get_right_child();
+
+            for (child, key) in cells {
+                print_tree(pager, child, indentation_level + 1)?;
+
+                indent(indentation_level + 1);
+                println!("- key {}", key);
             }
-            NodeType::Leaf => {
-                let node = LeafNode::new(node);
-                let num_keys = node.get_num_cells();
-                indent(indentation_level);
-                println!("- leaf (size {})", num_keys);
-                for i in 0..num_keys {
-                    indent(indentation_level + 1);
-                    println!("- {}", node.get_key(i));
-                }
+            if right_child != INVALID_PAGE_NUM {
+                print_tree(pager, right_child, indentation_level + 1)?;
+            }
+        }
+        NodeType::Leaf => {
+            let node = LeafNode::new(node);
+            let num_keys = node.get_num_cells();
+            indent(indentation_level);
+            println!("- leaf (size {})", num_keys);
+            for i in 0..num_keys {
+                indent(indentation_level + 1);
+                println!("- {}", node.get_key(i));
             }
         }
     }
+    Ok(())
 }
 
-unsafe fn node_parent(node: *mut u8) -> *mut u8 {
-    let node = LeafNode::new(node);
-    node.parent()
-}
-
-unsafe fn get_node_max_key(pager: &mut Pager, node: *mut u8) -> u32 {
-    let node = LeafNode::new(node);
-    node.get_node_max_key(pager)
+#[derive(Default)]
+pub struct SpaceUsage {
+    pub leaf_pages: u32,
+    pub internal_pages: u32,
+    pub rows: u64,
+    pub internal_keys: u64,
+    pub leaf_capacity: u64,
+    pub internal_capacity: u64,
 }
-
-struct Node {
-    data: *mut u8,
-}
-impl Node {
-    pub fn new(data: *mut u8) -> Self {
-        Self { data }
+impl SpaceUsage {
+    pub fn leaf_utilization(&self) -> f64 {
+        utilization(self.rows, self.leaf_capacity)
+    }
+    pub fn internal_utilization(&self) -> f64 {
+        utilization(self.internal_keys, self.internal_capacity)
     }
 }
-impl NodeTrait for Node {
-    fn data(&self) -> *mut u8 {
-        self.data
+impl std::fmt::Display for SpaceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Leaf pages: {}", self.leaf_pages)?;
+        writeln!(f, "Internal pages: {}", self.internal_pages)?;
+        writeln!(f, "Rows: {}", self.rows)?;
+        writeln!(f, "Leaf utilization: {:.1}%", self.leaf_utilization())?;
+        write!(
+            f,
+            "Internal utilization: {:.1}%",
+            self.internal_utilization()
+        )
     }
-    unsafe fn get_node_max_key(&self, pager: &mut Pager) -> u32 {
-        panic!();
+}
+
+fn utilization(used: u64, capacity: u64) -> f64 {
+    if capacity == 0 {
+        return 0.0;
     }
+    used as f64 * 100.0 / capacity as f64
 }
 
-pub trait NodeTrait {
-    fn data(&self) -> *mut u8;
+pub fn space_usage(pager: &mut Pager, page_num: u32, usage: &mut SpaceUsage) -> Result<()> {
+    let layout = *pager.layout();
+    let node = pager.page(page_num)?;
+
+    match get_node_type(&node)? {
+        NodeType::Internal => {
+            let node = InternalNode::new(node);
+            let num_keys = node.get_num_keys();
+            usage.internal_pages += 1;
+            usage.internal_keys += num_keys as u64;
+            usage.internal_capacity += layout.internal_node_max_cells as u64;
+
+            let mut children = (0..num_keys)
+                .map(|i| node.get_child(i))
+                .collect::<Result<Vec<_>>>()?;
+            let right_child = node.get_right_child();
+            if right_child != INVALID_PAGE_NUM {
+                children.push(right_child);
+            }
+            for child in children {
+                space_usage(pager, child, usage)?;
+            }
+        }
+        NodeType::Leaf => {
+            let node = LeafNode::new(node);
+            usage.leaf_pages += 1;
+            usage.rows += node.get_num_cells() as u64;
+            usage.leaf_capacity += layout.leaf_node_max_cells as u64;
+        }
+    }
+    Ok(())
+}
 
-    unsafe fn get_node_max_key(&self, pager: &mut Pager) -> u32 {
-        match self.get_node_type() {
+// The largest key in the subtree rooted at `page_num`
+fn get_node_max_key(pager: &mut Pager, page_num: u32) -> Result<u32> {
+    let mut page_num = page_num;
+    loop {
+        let node = pager.page(page_num)?;
+        match get_node_type(&node)? {
             NodeType::Internal => {
-                let right_child_page_num = get_internal_node_right_child(self.data());
-                let right_child = pager.page(right_child_page_num);
-                get_node_max_key(pager, right_child)
+                let right_child = InternalNode::new(node).get_right_child();
+                if right_child == INVALID_PAGE_NUM {
+                    return Err(DbError::Corrupt { page: page_num });
+                }
+                page_num = right_child;
             }
             NodeType::Leaf => {
-                let num_cells = get_leaf_node_num_cells(self.data());
-                get_leaf_node_key(self.data(), num_cells - 1)
+                let node = LeafNode::new(node);
+                let last_cell = node
+                    .get_num_cells()
+                    .checked_sub(1)
+                    .ok_or(DbError::Corrupt { page: page_num })?;
+                return Ok(node.get_key(last_cell));
             }
         }
     }
+}
+
+// A node whose type doesn't matter, for the fields in the common header
+struct Node<P> {
+    page: P,
+}
+impl<P: PageRead> Node<P> {
+    pub fn new(page: P) -> Self {
+        Self { page }
+    }
+}
+impl<P: PageRead> NodeTrait for Node<P> {
+    type Page = P;
 
-    unsafe fn is_root(&self) -> bool {
-        let value = std::ptr::read(self.data().add(IS_ROOT_OFFSET) as *const u8);
-        return if value == 0 {
-            false
-        } else if value == 1 {
-            true
-        } else {
-            panic!("invalid value");
-        };
+    fn page(&self) -> &P {
+        &self.page
     }
-    unsafe fn set_root(&self, is_root: bool) {
-        std::ptr::write(
-            self.data().add(IS_ROOT_OFFSET),
-            if is_root == true { 1u8 } else { 0u8 },
-        );
+    fn page_mut(&mut self) -> &mut P {
+        &mut self.page
     }
-    unsafe fn parent(&self) -> *mut u8 {
-        self.data().add(PARENT_POINTER_OFFSET)
+}
+
+pub trait NodeTrait {
+    type Page: PageRead;
+
+    fn page(&self) -> &Self::Page;
+    fn page_mut(&mut self) -> &mut Self::Page;
+
+    fn page_num(&self) -> u32 {
+        self.page().num()
     }
 
-    unsafe fn get_parent(&self) -> u32 {
-        std::ptr::read(self.data().add(PARENT_POINTER_OFFSET) as *const u32)
+    fn is_root(&self) -> Result<bool> {
+        match self.page().read_u8(IS_ROOT_OFFSET) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DbError::Corrupt {
+                page: self.page_num(),
+            }),
+        }
     }
-    unsafe fn set_parent(&self, parent: u32) {
-        std::ptr::write(self.data().add(PARENT_POINTER_OFFSET) as *mut u32, parent);
+    fn set_root(&mut self, is_root: bool)
+    where
+        Self::Page: PageWrite,
+    {
+        self.page_mut().write_u8(IS_ROOT_OFFSET, is_root as u8);
     }
 
-    unsafe fn get_node_type(&self) -> NodeType {
-        let value = *self.data().add(NODE_TYPE_OFFSET);
-        value.into()
+    fn get_parent(&self) -> u32 {
+        self.page().read_u32(PARENT_POINTER_OFFSET)
     }
-    unsafe fn set_node_type(&self, node_type: NodeType) {
-        let value = node_type.into();
-        std::ptr::write(self.data().add(NODE_TYPE_OFFSET), value);
+    fn set_parent(&mut self, parent: u32)
+    where
+        Self::Page: PageWrite,
+    {
+        self.page_mut().write_u32(PARENT_POINTER_OFFSET, parent);
     }
-}
-unsafe fn is_node_root(node: *mut u8) -> bool {
-    let node = LeafNode::new(node);
-    node.is_root()
-}
 
-unsafe fn copy_node(src: *mut u8, dest: *mut u8) {
-    std::ptr::copy_nonoverlapping(src, dest, PAGE_SIZE as usize);
+    fn get_node_type(&self) -> Result<NodeType> {
+        get_node_type(self.page())
+    }
+    fn set_node_type(&mut self, node_type: NodeType)
+    where
+        Self::Page: PageWrite,
+    {
+        self.page_mut().write_u8(NODE_TYPE_OFFSET, node_type.into());
+    }
 }
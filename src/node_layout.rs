@@ -1,4 +1,4 @@
-use crate::pager::PAGE_SIZE;
+use crate::checksum::CHECKSUM_SIZE;
 use crate::row::ROW_SIZE;
 
 // Node header (common header)
@@ -24,8 +24,6 @@ pub const LEAF_NODE_KEY_OFFSET: usize = 0;
 pub const LEAF_NODE_VALUE_SIZE: usize = ROW_SIZE;
 pub const LEAF_NODE_VALUE_OFFSET: usize = LEAF_NODE_KEY_OFFSET + LEAF_NODE_KEY_SIZE;
 pub const LEAF_NODE_CELL_SIZE: usize = LEAF_NODE_KEY_SIZE + LEAF_NODE_VALUE_SIZE;
-pub const LEAF_NODE_SPACE_FOR_CELLS: usize = PAGE_SIZE as usize - LEAF_NODE_HEADER_SIZE;
-pub const LEAF_NODE_MAX_CELLS: usize = LEAF_NODE_SPACE_FOR_CELLS / LEAF_NODE_CELL_SIZE;
 
 // Internal node head
 pub const INTERNAL_NODE_NUM_KEYS_SIZE: usize = std::mem::size_of::<u32>();
@@ -42,17 +40,65 @@ pub const INTERNAL_NODE_KEY_SIZE: usize = std::mem::size_of::<u32>();
 pub const INTERNAL_NODE_CHILD_SIZE: usize = std::mem::size_of::<u32>();
 pub const INTERNAL_NODE_CELL_SIZE: usize = INTERNAL_NODE_CHILD_SIZE + INTERNAL_NODE_KEY_SIZE;
 
-pub const INTERNAL_NODE_MAX_CELLS: usize = 3; // for testing
+// Everything that depends on the page size, which is chosen per database file
+#[derive(Clone, Copy)]
+pub struct NodeLayout {
+    pub page_size: usize,
+    pub leaf_node_space_for_cells: usize,
+    pub leaf_node_max_cells: usize,
+    pub leaf_node_left_split_count: usize,
+    pub leaf_node_right_split_count: usize,
+    pub internal_node_space_for_cells: usize,
+    pub internal_node_max_cells: usize,
+}
+impl NodeLayout {
+    // Nodes leave the end of the page to its checksum, if it has one
+    pub fn new(page_size: u32, checksums: bool) -> Self {
+        let page_size = page_size as usize;
+        let usable_size = if checksums {
+            page_size - CHECKSUM_SIZE
+        } else {
+            page_size
+        };
+
+        let leaf_node_space_for_cells = usable_size - LEAF_NODE_HEADER_SIZE;
+        let leaf_node_max_cells = leaf_node_space_for_cells / LEAF_NODE_CELL_SIZE;
+        let leaf_node_left_split_count = (leaf_node_max_cells + 1).div_ceil(2);
+        let leaf_node_right_split_count = (leaf_node_max_cells + 1) - leaf_node_left_split_count;
 
-pub const LEAF_NODE_LEFT_SPLIT_COUNT: usize = (LEAF_NODE_MAX_CELLS + 1) / 2;
-pub const LEAF_NODE_RIGHT_SPLIT_COUNT: usize =
-    (LEAF_NODE_MAX_CELLS + 1) - LEAF_NODE_LEFT_SPLIT_COUNT;
+        let internal_node_space_for_cells = usable_size - INTERNAL_NODE_HEADER_SIZE;
+        let internal_node_max_cells = internal_node_max_cells(internal_node_space_for_cells);
+
+        Self {
+            page_size,
+            leaf_node_space_for_cells,
+            leaf_node_max_cells,
+            leaf_node_left_split_count,
+            leaf_node_right_split_count,
+            internal_node_space_for_cells,
+            internal_node_max_cells,
+        }
+    }
+}
+
+#[cfg(not(feature = "small-internal-nodes"))]
+fn internal_node_max_cells(space_for_cells: usize) -> usize {
+    space_for_cells / INTERNAL_NODE_CELL_SIZE
+}
+// Tiny internal nodes let tests build multi-level trees with only a few rows
+#[cfg(feature = "small-internal-nodes")]
+fn internal_node_max_cells(_space_for_cells: usize) -> usize {
+    3
+}
 
-pub fn print_constants() {
+pub fn print_constants(layout: &NodeLayout) {
     println!("ROW_SIZE: {}", ROW_SIZE);
     println!("COMMON_NODE_HEADER_SIZE: {}", COMMON_NODE_HEADER_SIZE);
     println!("LEAF_NODE_HEADER_SIZE: {}", LEAF_NODE_HEADER_SIZE);
     println!("LEAF_NODE_CELL_SIZE: {}", LEAF_NODE_CELL_SIZE);
-    println!("LEAF_NODE_SPACE_FOR_CELLS: {}", LEAF_NODE_SPACE_FOR_CELLS);
-    println!("LEAF_NODE_MAX_CELLS: {}", LEAF_NODE_MAX_CELLS);
+    println!(
+        "LEAF_NODE_SPACE_FOR_CELLS: {}",
+        layout.leaf_node_space_for_cells
+    );
+    println!("LEAF_NODE_MAX_CELLS: {}", layout.leaf_node_max_cells);
 }
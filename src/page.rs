@@ -0,0 +1,141 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// A page in the pager's cache, empty until it is first read
+pub type Frame = RwLock<Option<Box<[u8]>>>;
+
+// Views of a page in the pager's cache. They borrow the pager, so a page can't be
+// evicted or reallocated while a node is looking at it, and every access is a
+// bounds-checked slice index. Multi-byte values are stored little-endian.
+pub struct PageRef<'a> {
+    num: u32,
+    data: &'a [u8],
+}
+impl<'a> PageRef<'a> {
+    pub fn new(num: u32, data: &'a [u8]) -> Self {
+        Self { num, data }
+    }
+}
+impl Deref for PageRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.data
+    }
+}
+
+// A page read through a pager shared between threads. It holds the frame's read
+// latch, so no other thread can be filling the frame in at the same time.
+pub struct LatchedPage<'a> {
+    num: u32,
+    frame: RwLockReadGuard<'a, Option<Box<[u8]>>>,
+}
+impl<'a> LatchedPage<'a> {
+    // `frame` must already hold the page
+    pub fn new(num: u32, frame: RwLockReadGuard<'a, Option<Box<[u8]>>>) -> Self {
+        Self { num, frame }
+    }
+}
+impl Deref for LatchedPage<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.frame.as_deref().expect("Page is not loaded")
+    }
+}
+
+// A page changed through a shared pager, holding the frame's write latch
+pub struct LatchedPageMut<'a> {
+    num: u32,
+    frame: RwLockWriteGuard<'a, Option<Box<[u8]>>>,
+}
+impl<'a> LatchedPageMut<'a> {
+    // `frame` must already hold the page
+    pub fn new(num: u32, frame: RwLockWriteGuard<'a, Option<Box<[u8]>>>) -> Self {
+        Self { num, frame }
+    }
+}
+impl Deref for LatchedPageMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.frame.as_deref().expect("Page is not loaded")
+    }
+}
+impl DerefMut for LatchedPageMut<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.frame.as_deref_mut().expect("Page is not loaded")
+    }
+}
+
+pub struct PageMut<'a> {
+    num: u32,
+    data: &'a mut [u8],
+}
+impl<'a> PageMut<'a> {
+    pub fn new(num: u32, data: &'a mut [u8]) -> Self {
+        Self { num, data }
+    }
+}
+impl Deref for PageMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.data
+    }
+}
+impl DerefMut for PageMut<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.data
+    }
+}
+
+pub trait PageRead: Deref<Target = [u8]> {
+    fn num(&self) -> u32;
+
+    fn read_u8(&self, offset: usize) -> u8 {
+        self[offset]
+    }
+    fn read_u32(&self, offset: usize) -> u32 {
+        read_u32(self, offset)
+    }
+}
+impl PageRead for PageRef<'_> {
+    fn num(&self) -> u32 {
+        self.num
+    }
+}
+impl PageRead for LatchedPage<'_> {
+    fn num(&self) -> u32 {
+        self.num
+    }
+}
+impl PageRead for LatchedPageMut<'_> {
+    fn num(&self) -> u32 {
+        self.num
+    }
+}
+impl PageRead for PageMut<'_> {
+    fn num(&self) -> u32 {
+        self.num
+    }
+}
+
+pub trait PageWrite: PageRead + DerefMut {
+    fn write_u8(&mut self, offset: usize, value: u8) {
+        self[offset] = value;
+    }
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        write_u32(self, offset, value);
+    }
+}
+impl PageWrite for PageMut<'_> {}
+impl PageWrite for LatchedPageMut<'_> {}
+
+pub fn read_u32(src: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(src[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn write_u32(dest: &mut [u8], offset: usize, value: u32) {
+    dest[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
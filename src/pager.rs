@@ -1,140 +1,797 @@
-use libc::EXIT_FAILURE;
+use crate::checksum::{checksum_matches, write_checksum};
+use crate::error::{DbError, Result};
+use crate::free_list::{truncated_num_pages, write_free_list, FreeList};
+use crate::header::{AutoVacuum, DbHeader, StorageMode, HEADER_PAGE_NUM, HEADER_SIZE};
+use crate::journal::Journal;
+use crate::lock::{self, LockLevel, DEFAULT_BUSY_TIMEOUT};
+use crate::node::check_cell_count;
+use crate::node_layout::NodeLayout;
+use crate::page::{Frame, LatchedPage, LatchedPageMut, PageMut, PageRead, PageRef};
+use crate::snapshot::Versions;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::process::exit;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
 
-pub const PAGE_SIZE: u32 = 4096;
+pub const DEFAULT_PAGE_SIZE: u32 = 4096;
+pub const MIN_PAGE_SIZE: u32 = 512;
+pub const MAX_PAGE_SIZE: u32 = 65536;
 pub const TABLE_MAX_PAGES: u32 = 100;
+// Opening this name gives a database that lives only in the page cache
+pub const MEMORY_DB_NAME: &str = ":memory:";
+
+pub fn is_valid_page_size(page_size: u32) -> bool {
+    page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+}
+
+// How long writing back changes waits for the disk. Changes are only written back
+// when the connection closes, which is what commits them, so that is the only time
+// any level waits. Pages that have been written are in the OS's cache, so every
+// level survives the process crashing. They differ in what survives the machine
+// crashing or losing power.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Synchronous {
+    // Never waits. The latest changes may be lost, and the file may be left with
+    // only some of them.
+    Off,
+    // Waits only where a copy-on-write file needs its new pages on disk before the
+    // header that points at them. The tree in the file stays whole, but the latest
+    // changes may be lost. In-place files are the same as with `Off`.
+    Normal,
+    // Also waits once everything is written, so changes that were written back
+    // survive. An in-place file may still be left with only some of them if the
+    // crash comes while they are being written.
+    #[default]
+    Full,
+}
+impl Synchronous {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "off" => Some(Synchronous::Off),
+            "normal" => Some(Synchronous::Normal),
+            "full" => Some(Synchronous::Full),
+            _ => None,
+        }
+    }
+}
 
 pub struct Pager {
-    file: File,
-    num_pages: u32,
-    pages: [Option<[u8; PAGE_SIZE as usize]>; TABLE_MAX_PAGES as usize],
+    // None for an in-memory database. Shared with snapshots, which read the pages
+    // nothing has changed straight from the file.
+    file: Option<Arc<File>>,
+    // The name the file was opened by, which a vacuum may point at a new file
+    path: String,
+    page_size: u32,
+    // Whether pages end in a checksum, which is checked as they are read
+    checksums: bool,
+    layout: NodeLayout,
+    // Atomic, like the latched frames, so threads sharing the pager can load pages
+    num_pages: AtomicU32,
+    // Bumped whenever the pages seen through the cache may have changed, so a
+    // backup can tell it has to start over
+    data_version: AtomicU64,
+    frames: Vec<Frame>,
+    // Pages changed through `page_mut` since the last commit
+    changed: Vec<bool>,
+    versions: Arc<Versions>,
+    // Opened without write access, so it never takes a write lock
+    read_only: bool,
+    storage_mode: StorageMode,
+    free_list: FreeList,
+    journal: Journal,
+    lock: LockLevel,
+    // Statements holding the shared lock through `lock_shared`
+    shared_holds: u32,
+    busy_timeout: Duration,
+    synchronous: Synchronous,
+    // How many times the file has been synced, to show what each level waits for
+    syncs: AtomicU32,
+    // The file's change counter when the cache was filled, if it has a header yet
+    change_counter: Option<u32>,
 }
 
 impl Pager {
-    pub fn open(filename: &String) -> Self {
-        let file = match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)
-        {
-            Ok(f) => f,
-            Err(_) => {
-                println!("Unable to open file");
-                exit(EXIT_FAILURE);
+    // `page_size` and `storage_mode` are only used when creating a new database
+    // file. Existing files keep the ones recorded in their header. A read-only pager
+    // never creates or writes the file.
+    pub fn open(
+        filename: &str,
+        page_size: u32,
+        storage_mode: StorageMode,
+        read_only: bool,
+    ) -> Result<Self> {
+        if !is_valid_page_size(page_size) {
+            return Err(DbError::InvalidPageSize);
+        }
+        if filename == MEMORY_DB_NAME {
+            let mut pager = Self::new(None, filename, page_size, storage_mode, read_only);
+            pager.reload()?;
+            return Ok(pager);
+        }
+
+        let file = open_file(filename, read_only)?;
+
+        // Read the header under a shared lock so a writer can't be halfway through it
+        let mut pager = Self::new(
+            Some(Arc::new(file)),
+            filename,
+            page_size,
+            storage_mode,
+            read_only,
+        );
+        pager.lock_shared()?;
+        pager.unlock_shared()?;
+        Ok(pager)
+    }
+
+    fn new(
+        file: Option<Arc<File>>,
+        path: &str,
+        page_size: u32,
+        storage_mode: StorageMode,
+        read_only: bool,
+    ) -> Self {
+        Pager {
+            versions: Arc::new(Versions::new(file.clone(), page_size, true)),
+            file,
+            path: path.to_string(),
+            page_size,
+            checksums: true,
+            layout: NodeLayout::new(page_size, true),
+            num_pages: AtomicU32::new(0),
+            data_version: AtomicU64::new(0),
+            frames: (0..TABLE_MAX_PAGES).map(|_| Frame::default()).collect(),
+            changed: vec![false; TABLE_MAX_PAGES as usize],
+            read_only,
+            storage_mode,
+            free_list: FreeList::default(),
+            journal: Journal::default(),
+            lock: LockLevel::Unlocked,
+            shared_holds: 0,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            synchronous: Synchronous::default(),
+            syncs: AtomicU32::new(0),
+            change_counter: None,
+        }
+    }
+
+    // Drops every cached page and picks up the file as it is now. An empty file is a
+    // new database, whose header only exists in the cache until it is written back.
+    fn reload(&mut self) -> Result<()> {
+        let file_length = match &self.file {
+            Some(file) => file.metadata()?.len(),
+            None => 0,
+        };
+        self.change_counter = None;
+        self.free_list = FreeList::default();
+        // New files always get checksums
+        self.checksums = true;
+        if file_length > 0 {
+            let file = self.file.as_ref().unwrap();
+            let header = read_header(file)?;
+            self.page_size = header.page_size;
+            self.checksums = header.checksums;
+            self.change_counter = Some(header.change_counter);
+            self.storage_mode = header.storage_mode;
+            if header.free_page_count > 0 {
+                let header_page =
+                    read_page(Some(file), self.page_size, self.checksums, HEADER_PAGE_NUM)?;
+                self.free_list = FreeList::read(&header_page, &header)?;
+            }
+        }
+        self.layout = NodeLayout::new(self.page_size, self.checksums);
+
+        if !file_length.is_multiple_of(self.page_size as u64) {
+            // Db file is not a whole number of pages. The last page is cut short.
+            return Err(DbError::Corrupt {
+                page: (file_length / self.page_size as u64) as u32,
+            });
+        }
+
+        let num_pages = (file_length / self.page_size as u64) as u32;
+        self.num_pages.store(num_pages, Ordering::Relaxed);
+        self.data_version.fetch_add(1, Ordering::Relaxed);
+        self.evict_all();
+        self.versions
+            .reset(self.file.clone(), self.page_size, self.checksums);
+        self.journal.reloaded(num_pages, &self.free_list);
+        if num_pages == 0 {
+            self.write_header(&DbHeader::new(self.page_size, self.storage_mode))?;
+        }
+        Ok(())
+    }
+
+    // Keeps the cache if no other connection has written since it was filled
+    fn refresh(&mut self) -> Result<()> {
+        if let (Some(file), Some(change_counter)) = (&self.file, self.change_counter) {
+            if read_header(file)?.change_counter == change_counter {
+                return Ok(());
+            }
+        }
+        self.reload()
+    }
+
+    pub fn set_busy_timeout(&mut self, timeout: Duration) {
+        self.busy_timeout = timeout;
+    }
+
+    pub fn set_synchronous(&mut self, synchronous: Synchronous) {
+        self.synchronous = synchronous;
+    }
+
+    pub fn sync_count(&self) -> u32 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    // Taken for each statement. Statements can overlap, such as a check run while
+    // other threads' queries are reading, so the lock is only released once the
+    // last of them is done.
+    pub fn lock_shared(&mut self) -> Result<()> {
+        self.acquire_shared()?;
+        self.shared_holds += 1;
+        Ok(())
+    }
+
+    // Releases the statement's shared lock, unless another statement still holds
+    // it or this connection is writing
+    pub fn unlock_shared(&mut self) -> Result<()> {
+        self.shared_holds = self.shared_holds.saturating_sub(1);
+        if self.shared_holds == 0 && self.lock == LockLevel::Shared {
+            self.unlock()?;
+        }
+        Ok(())
+    }
+
+    // While nothing else holds a lock, another connection may have changed the
+    // file, so the cache is checked whenever the lock is newly acquired
+    fn acquire_shared(&mut self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if self.lock >= LockLevel::Shared {
+            return Ok(());
+        }
+
+        lock::lock_shared(file, self.busy_timeout)?;
+        self.lock = LockLevel::Shared;
+        if let Err(e) = self.follow_replaced_file().and_then(|()| self.refresh()) {
+            self.unlock()?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Taken before the first change and held until the pages are written back on
+    // close, so only one connection at a time has unsaved changes
+    pub fn lock_reserved(&mut self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if self.lock >= LockLevel::Reserved {
+            return Ok(());
+        }
+
+        let was_shared = self.lock == LockLevel::Shared;
+        if was_shared && lock::try_lock_reserved(file)? {
+            self.lock = LockLevel::Reserved;
+            return Ok(());
+        }
+        // Another writer needs every reader gone before it can save its changes, so
+        // don't keep it waiting on our shared lock while we wait for it
+        self.unlock()?;
+        loop {
+            let file = self.file.as_ref().unwrap();
+            if let Err(e) = lock::lock_reserved(file, self.busy_timeout) {
+                // Statements that were already reading still need their shared lock
+                if was_shared {
+                    self.acquire_shared()?;
+                }
+                return Err(e);
+            }
+            // Once RESERVED is held no vacuum can replace the file
+            if !self.file_replaced()? {
+                break;
             }
+            self.unlock()?;
+            self.reopen()?;
+        }
+        if let Err(e) = self.acquire_shared() {
+            self.unlock()?;
+            return Err(e);
+        }
+        self.lock = LockLevel::Reserved;
+        Ok(())
+    }
+
+    // A vacuum replaces the file under its name, so a connection that waited for
+    // a lock on the old one switches to the new one and takes the lock again
+    fn follow_replaced_file(&mut self) -> Result<()> {
+        while self.file_replaced()? {
+            self.unlock()?;
+            self.reopen()?;
+            lock::lock_shared(self.file.as_ref().unwrap(), self.busy_timeout)?;
+            self.lock = LockLevel::Shared;
+        }
+        Ok(())
+    }
+
+    fn file_replaced(&self) -> Result<bool> {
+        let Some(file) = &self.file else {
+            return Ok(false);
+        };
+        let current = match std::fs::metadata(&self.path) {
+            Ok(current) => current,
+            // Deleted rather than replaced, so there is nothing to switch to
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
         };
+        let opened = file.metadata()?;
+        Ok((current.dev(), current.ino()) != (opened.dev(), opened.ino()))
+    }
+
+    // The cache belongs to the old file, so the next refresh reloads it
+    fn reopen(&mut self) -> Result<()> {
+        self.file = Some(Arc::new(open_file(&self.path, self.read_only)?));
+        self.change_counter = None;
+        Ok(())
+    }
 
-        let file_length = file.metadata().unwrap().len() as u32;
-        if file_length % PAGE_SIZE != 0 {
-            println!("Db file is not a whole number of pages. Corrupt file.");
-            exit(EXIT_FAILURE);
+    // Puts the database file at `path` in place of this one, once no other
+    // connection is reading. This connection must be writing, and whatever it
+    // hasn't written back is dropped, so the new file must already hold it.
+    pub fn replace_file(&mut self, path: &str) -> Result<()> {
+        let file = self.file.as_ref().unwrap();
+        lock::lock_exclusive(file, self.busy_timeout)?;
+        if let Err(e) = std::fs::rename(path, &self.path) {
+            lock::downgrade_to_reserved(file)?;
+            return Err(e.into());
         }
 
-        Pager {
-            file,
-            num_pages: file_length / PAGE_SIZE,
-            pages: [None; TABLE_MAX_PAGES as usize],
+        self.unlock()?;
+        self.reopen()?;
+        // Statements still reading keep a shared lock on the new file
+        self.acquire_shared()?;
+        if self.shared_holds == 0 {
+            self.unlock()?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        if let Some(file) = &self.file {
+            lock::unlock(file)?;
+        }
+        self.lock = LockLevel::Unlocked;
+        Ok(())
+    }
+
+    // Writes back any changes and drops the cache. If readers keep the database busy
+    // past the timeout, the changes stay cached so closing can be retried.
+    pub fn close(&mut self) -> Result<()> {
+        if self.lock >= LockLevel::Reserved {
+            let file = self.file.as_ref().unwrap();
+            lock::lock_exclusive(file, self.busy_timeout)?;
+            self.lock = LockLevel::Exclusive;
+
+            let mut header = self.header()?;
+            header.change_counter = header.change_counter.wrapping_add(1);
+            let mut free_pages = self.free_list.pages();
+            // Free pages at the end of the file are left out of the header's list,
+            // and cut off once it is written
+            let num_pages = match header.auto_vacuum {
+                AutoVacuum::None => self.num_pages(),
+                AutoVacuum::Full | AutoVacuum::Incremental => {
+                    truncated_num_pages(self.num_pages(), &free_pages)
+                }
+            };
+            free_pages.retain(|&page_num| page_num < num_pages);
+            header.free_page_count = free_pages.len() as u32;
+            self.write_header(&header)?;
+            write_free_list(&mut self.page_mut(HEADER_PAGE_NUM)?, &free_pages);
+
+            // The header goes last, once every page its root reaches is in the file
+            for page_number in 1..num_pages {
+                self.flush_page(page_number)?;
+            }
+            // Copy-on-write promises that a crash can't leave the header pointing at
+            // pages that were never written, so it waits for the disk in between
+            if self.storage_mode == StorageMode::CopyOnWrite {
+                self.sync(Synchronous::Normal)?;
+            }
+            self.flush_page(HEADER_PAGE_NUM)?;
+            self.sync(Synchronous::Full)?;
+            self.free_list.committed();
+            if num_pages < self.num_pages() {
+                self.truncate(num_pages)?;
+            }
+        }
+
+        // Savepoints still open are released, keeping their changes
+        self.journal.clear();
+        self.unlock()?;
+        self.evict_all();
+        Ok(())
+    }
+
+    // Cuts the file short once the header no longer lists the pages past the new
+    // end. A crash before this leaves them in the file unused until a vacuum.
+    fn truncate(&mut self, num_pages: u32) -> Result<()> {
+        if let Some(file) = &self.file {
+            file.set_len(self.page_offset(num_pages))?;
         }
+        self.sync(Synchronous::Full)?;
+        self.free_list.truncate(num_pages);
+        self.num_pages.store(num_pages, Ordering::Relaxed);
+        self.data_version.fetch_add(1, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn close(&mut self) {
-        for page_number in 0..self.num_pages {
-            if self.pages[page_number as usize].is_none() {
-                continue;
+    // Waits for the writes so far to reach the disk, if the setting asks for at
+    // least `level`
+    fn sync(&self, level: Synchronous) -> Result<()> {
+        if let Some(file) = &self.file {
+            if self.synchronous >= level {
+                file.sync_data()?;
+                self.syncs.fetch_add(1, Ordering::Relaxed);
             }
-            self.flush_page(page_number);
-            self.pages[page_number as usize] = None;
         }
+        Ok(())
     }
 
-    fn flush_page(&mut self, page_num: u32) {
-        if self.pages[page_num as usize].is_none() {
-            println!("Tried to flush null page");
-            exit(EXIT_FAILURE);
+    fn evict_all(&mut self) {
+        for page_num in 0..TABLE_MAX_PAGES {
+            *self.frame_mut(page_num) = None;
         }
+        self.changed.fill(false);
+    }
+
+    pub fn savepoint(&mut self, name: &str) {
+        self.journal
+            .savepoint(name, self.num_pages(), &self.free_list);
+    }
 
-        match self
-            .file
-            .seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))
-        {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Error seeking: {:?}", e.raw_os_error());
+    pub fn release(&mut self, name: &str) -> Result<()> {
+        self.journal.release(name)
+    }
+
+    // Puts back every page changed since the savepoint was set. Pages added since
+    // are dropped from the cache, so they read as zeroes or as the file has them.
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        let rollback = self.journal.rollback_to(name)?;
+        for (page_num, before_image) in rollback.pages {
+            if page_num < rollback.num_pages {
+                *self.frame_mut(page_num) = Some(before_image);
+                self.changed[page_num as usize] = true;
             }
         }
+        for page_num in rollback.num_pages..self.num_pages() {
+            *self.frame_mut(page_num) = None;
+            self.changed[page_num as usize] = false;
+        }
+        self.num_pages.store(rollback.num_pages, Ordering::Relaxed);
+        self.data_version.fetch_add(1, Ordering::Relaxed);
+        self.free_list = rollback.free_list;
+        Ok(())
+    }
+
+    // While a savepoint is set every change has to go through `page_mut`, so its
+    // before-image is recorded
+    pub fn in_savepoint(&self) -> bool {
+        self.journal.is_active()
+    }
+
+    // The versions that queries read, which only change when a commit publishes
+    // them
+    pub fn versions(&self) -> Arc<Versions> {
+        self.versions.clone()
+    }
+
+    // Makes the pages changed through `page_mut` since the last commit visible to
+    // snapshots taken from now on
+    pub fn commit(&mut self) {
+        let mut changed = Vec::new();
+        for page_num in 0..TABLE_MAX_PAGES {
+            if std::mem::take(&mut self.changed[page_num as usize]) {
+                if let Some(page) = self.frame_mut(page_num).as_deref() {
+                    changed.push((page_num, Box::from(page)));
+                }
+            }
+        }
+        self.versions.publish(changed);
+    }
+
+    // Commits a page changed through a shared pager. Its latch must still be held,
+    // so commits to the same page are published in the order they were made.
+    pub fn commit_page(&self, page: &LatchedPageMut) {
+        self.versions.publish([(page.num(), Box::from(&page[..]))]);
+    }
+
+    fn flush_page(&mut self, page_num: u32) -> Result<()> {
+        let offset = self.page_offset(page_num);
+        let Some(page) = self.frames[page_num as usize]
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+        else {
+            return Ok(());
+        };
+
+        if self.checksums {
+            write_checksum(page);
+        }
+        if let Some(file) = &self.file {
+            file.write_all_at(page, offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn header(&mut self) -> Result<DbHeader> {
+        DbHeader::read(&self.page(HEADER_PAGE_NUM)?).ok_or(DbError::Corrupt {
+            page: HEADER_PAGE_NUM,
+        })
+    }
+
+    pub fn write_header(&mut self, header: &DbHeader) -> Result<()> {
+        header.write(&mut self.page_mut(HEADER_PAGE_NUM)?);
+        Ok(())
+    }
 
-        let page = self.pages[page_num as usize].as_ref().unwrap();
-        match self.file.write(page) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Error writing: {:?}", e.raw_os_error());
-                exit(EXIT_FAILURE);
+    pub fn storage_mode(&self) -> StorageMode {
+        self.storage_mode
+    }
+
+    // A zeroed page for a copy-on-write tree, reusing a free one if it can
+    pub fn allocate_page(&mut self) -> Result<u32> {
+        let page_num = match self.free_list.pop() {
+            Some(page_num) => page_num,
+            None => {
+                let page_num = self.num_pages();
+                if page_num >= TABLE_MAX_PAGES {
+                    return Err(DbError::Full);
+                }
+                self.free_list.add_fresh(page_num);
+                page_num
             }
+        };
+        self.page_mut(page_num)?.fill(0);
+        Ok(page_num)
+    }
+
+    // Hands back a page the tree no longer uses
+    pub fn free_page(&mut self, page_num: u32) {
+        self.free_list.free(page_num);
+        // In memory nothing is written back, so there is no tree in a file that
+        // could still need the page
+        if self.file.is_none() {
+            self.free_list.committed();
+        }
+    }
+
+    pub fn free_pages(&self) -> Vec<u32> {
+        self.free_list.pages()
+    }
+
+    // How many free pages below `page_num` can be allocated now
+    pub fn reusable_pages_below(&self, page_num: u32) -> usize {
+        self.free_list.reusable_below(page_num)
+    }
+
+    // Changes whenever a page may read differently than it did before
+    pub fn data_version(&self) -> u64 {
+        self.data_version.load(Ordering::Relaxed)
+    }
+
+    // A page as writing back would put it in the file now. The free list is only
+    // stored in the header page as it is written back, so it is added here.
+    pub fn page_image(&mut self, page_num: u32) -> Result<Box<[u8]>> {
+        let mut page = Box::from(&*self.page(page_num)?);
+        if page_num == HEADER_PAGE_NUM {
+            let mut header = DbHeader::read(&page).ok_or(DbError::Corrupt {
+                page: HEADER_PAGE_NUM,
+            })?;
+            let free_pages = self.free_list.pages();
+            header.free_page_count = free_pages.len() as u32;
+            header.write(&mut page);
+            write_free_list(&mut page, &free_pages);
+        }
+        if self.checksums {
+            write_checksum(&mut page);
         }
+        Ok(page)
     }
 
     pub fn get_unused_page_num(&self) -> u32 {
-        self.num_pages
+        self.num_pages()
     }
 
-    pub fn file_size(&self) -> u64 {
-        self.file.metadata().unwrap().len()
+    pub fn file_size(&self) -> Result<u64> {
+        match &self.file {
+            Some(file) => Ok(file.metadata()?.len()),
+            None => Ok(0),
+        }
     }
 
-    pub fn page(&mut self, page_num: u32) -> *mut u8 {
-        if page_num >= TABLE_MAX_PAGES {
-            println!(
-                "Tried to fetch page number out of bounds. {} > {}",
-                page_num, TABLE_MAX_PAGES
-            );
-            exit(EXIT_FAILURE);
+    pub fn is_in_memory(&self) -> bool {
+        self.file.is_none()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Whether this connection holds the write lock, and so may have changes that
+    // aren't written back yet
+    pub fn is_writing(&self) -> bool {
+        self.lock >= LockLevel::Reserved
+    }
+
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    pub fn layout(&self) -> &NodeLayout {
+        &self.layout
+    }
+
+    pub fn page(&mut self, page_num: u32) -> Result<PageRef<'_>> {
+        let data = self.cached_page(page_num)?;
+        Ok(PageRef::new(page_num, data))
+    }
+
+    pub fn page_mut(&mut self, page_num: u32) -> Result<PageMut<'_>> {
+        self.frame(page_num)?;
+        if self.journal.needs_before_image(page_num) {
+            let before_image = Box::from(&*self.cached_page(page_num)?);
+            self.journal.record(page_num, before_image);
         }
+        self.changed[page_num as usize] = true;
+        self.data_version.fetch_add(1, Ordering::Relaxed);
+        let data = self.cached_page(page_num)?;
+        Ok(PageMut::new(page_num, data))
+    }
 
-        if self.pages[page_num as usize].is_none() {
+    // With the whole pager borrowed no other thread can be using it, so no latch
+    // is needed
+    fn cached_page(&mut self, page_num: u32) -> Result<&mut [u8]> {
+        self.frame(page_num)?;
+        if self.frame_mut(page_num).is_none() {
             // Cache miss. Allocate memory and load from file.
-            self.handle_page_miss(page_num);
+            let page = self.load_page(page_num)?;
+            *self.frame_mut(page_num) = Some(page);
+        }
+        Ok(self.frame_mut(page_num).as_mut().unwrap())
+    }
+
+    // Reads a page through a pager that other threads may be using too
+    pub fn latched_page(&self, page_num: u32) -> Result<LatchedPage<'_>> {
+        let frame = self.frame(page_num)?;
+        let page = read_latch(frame);
+        if page.is_some() {
+            return Ok(LatchedPage::new(page_num, page));
         }
+        drop(page);
 
-        self.pages[page_num as usize].as_ref().unwrap().as_ptr() as *mut _
+        // Cache miss. Another thread may have loaded the page while we waited.
+        drop(self.loaded_frame(page_num)?);
+        Ok(LatchedPage::new(page_num, read_latch(frame)))
     }
 
-    pub fn num_pages(&self) -> u32 {
-        self.num_pages
+    // Changes a page through a shared pager. Only pages that are already part of
+    // the tree can be changed this way, since allocating one needs the whole pager.
+    pub fn latched_page_mut(&self, page_num: u32) -> Result<LatchedPageMut<'_>> {
+        let page = self.loaded_frame(page_num)?;
+        self.data_version.fetch_add(1, Ordering::Relaxed);
+        Ok(LatchedPageMut::new(page_num, page))
     }
 
-    fn handle_page_miss(&mut self, page_num: u32) {
-        let mut page = [0; PAGE_SIZE as usize];
+    // The page's frame under its write latch, loaded from the file if it wasn't
+    // cached. Loading leaves the page as it was, so the data version stays the same.
+    fn loaded_frame(&self, page_num: u32) -> Result<RwLockWriteGuard<'_, Option<Box<[u8]>>>> {
+        let mut page = write_latch(self.frame(page_num)?);
+        if page.is_none() {
+            *page = Some(self.load_page(page_num)?);
+        }
+        Ok(page)
+    }
 
-        let file_size = self.file_size() as u32;
-        let mut num_pages = file_size / PAGE_SIZE;
-        if file_size % PAGE_SIZE != 0 {
-            num_pages += 1;
+    // Allocating checks that the table has room before asking for a new page, so a
+    // page past the end here came from a corrupt pointer
+    fn frame(&self, page_num: u32) -> Result<&Frame> {
+        if page_num >= TABLE_MAX_PAGES {
+            return Err(DbError::Corrupt { page: page_num });
         }
+        Ok(&self.frames[page_num as usize])
+    }
 
-        if page_num <= num_pages {
-            self.file
-                .seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))
-                .unwrap();
+    fn frame_mut(&mut self, page_num: u32) -> &mut Option<Box<[u8]>> {
+        self.frames[page_num as usize]
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
 
-            let len_to_read = if (page_num + 1) * PAGE_SIZE > file_size {
-                file_size - (page_num * PAGE_SIZE)
-            } else {
-                PAGE_SIZE
-            };
+    pub fn num_pages(&self) -> u32 {
+        self.num_pages.load(Ordering::Relaxed)
+    }
+
+    fn page_offset(&self, page_num: u32) -> u64 {
+        page_num as u64 * self.page_size as u64
+    }
+
+    fn load_page(&self, page_num: u32) -> Result<Box<[u8]>> {
+        let page = read_page(
+            self.file.as_deref(),
+            self.page_size,
+            self.checksums,
+            page_num,
+        )?;
+        self.num_pages.fetch_max(page_num + 1, Ordering::Relaxed);
+        Ok(page)
+    }
+}
 
-            if let Err(e) = self.file.read_exact(&mut page[0..len_to_read as usize]) {
-                println!("Error reading file: {:?}", e.raw_os_error());
-                exit(EXIT_FAILURE);
+// Reads a page from the file, or zeroes one past its end. With `checksums`, a page
+// from the file whose checksum doesn't match is reported as corrupt, and so is a
+// node claiming more cells than fit, whatever path it is reached by.
+pub fn read_page(
+    file: Option<&File>,
+    page_size: u32,
+    checksums: bool,
+    page_num: u32,
+) -> Result<Box<[u8]>> {
+    let mut page = vec![0; page_size as usize].into_boxed_slice();
+    let offset = page_num as u64 * page_size as u64;
+
+    if let Some(file) = file {
+        let file_size = file.metadata()?.len();
+        if offset < file_size {
+            let len_to_read = (file_size - offset).min(page_size as u64);
+            file.read_exact_at(&mut page[0..len_to_read as usize], offset)?;
+            if checksums && !checksum_matches(&page) {
+                return Err(DbError::Corrupt { page: page_num });
+            }
+            if page_num != HEADER_PAGE_NUM {
+                let layout = NodeLayout::new(page_size, checksums);
+                check_cell_count(&PageRef::new(page_num, &page), &layout)?;
             }
         }
+    }
+    Ok(page)
+}
+
+// A thread that panics holding a latch can't have left a frame half-filled, since
+// pages are only stored once they are fully read, so poisoning is ignored
+fn read_latch(frame: &Frame) -> RwLockReadGuard<'_, Option<Box<[u8]>>> {
+    frame.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_latch(frame: &Frame) -> RwLockWriteGuard<'_, Option<Box<[u8]>>> {
+    frame.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn open_file(path: &str, read_only: bool) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .create(!read_only)
+        .truncate(false)
+        .open(path)?)
+}
 
-        self.pages[page_num as usize] = Some(page);
+fn read_header(file: &File) -> Result<DbHeader> {
+    let mut header = [0; HEADER_SIZE];
+    file.read_exact_at(&mut header, 0)
+        .map_err(|_| DbError::NotADatabase)?;
 
-        if page_num >= self.num_pages {
-            self.num_pages = page_num + 1;
+    match DbHeader::read(&header) {
+        Some(header) if is_valid_page_size(header.page_size) => Ok(header),
+        None if DbHeader::is_baseline(&header, file.metadata()?.len()) => {
+            Err(DbError::OlderVersion)
         }
+        _ => Err(DbError::NotADatabase),
     }
 }
@@ -1,39 +1,58 @@
 use libc::EXIT_FAILURE;
 use std::io::Write;
 use std::process::exit;
+use std::time::Duration;
 
-use crate::statement::PrepareResult;
-use crate::table::Table;
-use crate::{
-    meta_command::do_meta_command,
-    statement::{execute_statement, prepare_statement},
-};
+use crate::connection::Connection;
+use crate::error::Result;
+use crate::header::StorageMode;
+use crate::meta_command::do_meta_command;
+use crate::pager::Synchronous;
 
-pub fn start(db_filename: String) {
-    let mut table = Table::new();
-    table.db_open(&db_filename);
+pub fn start(
+    db_filename: String,
+    page_size: u32,
+    storage_mode: StorageMode,
+    read_only: bool,
+    busy_timeout: Duration,
+    synchronous: Synchronous,
+) {
+    let conn = Connection::open_with_options(&db_filename, page_size, storage_mode, read_only);
+    let mut conn = match conn {
+        Ok(conn) => conn,
+        Err(e) => {
+            println!("{}", e);
+            exit(EXIT_FAILURE);
+        }
+    };
+    conn.set_busy_timeout(busy_timeout);
+    conn.set_synchronous(synchronous);
 
     loop {
         print_prompt();
         let input = read_input();
         if input.starts_with('.') {
-            if let Err(e) = do_meta_command(&input, &mut table) {
+            if let Err(e) = do_meta_command(&input, &mut conn) {
                 println!("{:?} '{}'", e, input);
             }
         } else {
-            match prepare_statement(&input) {
-                PrepareResult::Success(statement) => {
-                    let result = execute_statement(statement, &mut table);
-                    println!("{}", result.msg());
-                }
-                err => {
-                    println!("{}", err.err_msg(&input));
-                }
+            match execute(&input, &mut conn) {
+                Ok(()) => println!("Executed."),
+                Err(e) => println!("{}", e),
             }
         }
     }
 }
 
+fn execute(input: &str, conn: &mut Connection) -> Result<()> {
+    let mut statement = conn.prepare(input)?;
+    for row in statement.query(&[])? {
+        let values: Vec<String> = row?.iter().map(|value| value.to_string()).collect();
+        println!("({})", values.join(", "));
+    }
+    Ok(())
+}
+
 fn print_prompt() {
     print!("db > ");
     std::io::stdout().flush().expect("Failed to flush stdout");
@@ -43,7 +62,7 @@ fn read_input() -> String {
     let mut input = String::new();
 
     if let Ok(bytes_read) = std::io::stdin().read_line(&mut input) {
-        if bytes_read <= 0 {
+        if bytes_read == 0 {
             println!("Error reading input\n");
             exit(EXIT_FAILURE);
         }
@@ -1,13 +1,22 @@
+use crate::page::{read_u32, write_u32};
+use crate::value::Value;
 use std::fmt::Formatter;
 
 pub const COLUMN_USERNAME_SIZE: usize = 32;
 pub const COLUMN_EMAIL_SIZE: usize = 255;
+pub const COLUMN_NAMES: [&str; 3] = ["id", "username", "email"];
 
+#[derive(Clone)]
 pub struct Row {
     pub id: u32,
     pub username: [u8; COLUMN_USERNAME_SIZE],
     pub email: [u8; COLUMN_EMAIL_SIZE],
 }
+impl Default for Row {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Row {
     pub fn new() -> Self {
         Row {
@@ -16,6 +25,21 @@ impl Row {
             email: [0; COLUMN_EMAIL_SIZE],
         }
     }
+
+    pub fn values(&self) -> Vec<Value> {
+        vec![
+            Value::Integer(self.id as i64),
+            Value::Text(column_text(&self.username)),
+            Value::Text(column_text(&self.email)),
+        ]
+    }
+}
+
+// Text columns are stored NUL padded to their fixed width
+fn column_text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_matches('\u{0000}')
+        .to_string()
 }
 
 impl std::fmt::Display for Row {
@@ -24,12 +48,8 @@ impl std::fmt::Display for Row {
             f,
             "({}, {}, {})",
             self.id,
-            String::from_utf8_lossy(&self.username)
-                .to_string()
-                .trim_matches('\u{0000}'),
-            String::from_utf8_lossy(&self.email)
-                .to_string()
-                .trim_matches('\u{0000}')
+            column_text(&self.username),
+            column_text(&self.email)
         )
     }
 }
@@ -43,25 +63,16 @@ const USERNAME_OFFSET: usize = ID_OFFSET + ID_SIZE;
 const EMAIL_OFFSET: usize = USERNAME_OFFSET + USERNAME_SIZE;
 pub const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
 
-pub fn serialize_row(source: &Row, dest: *mut u8) {
-    unsafe {
-        std::ptr::write(dest.add(ID_OFFSET) as *mut u32, source.id);
-        std::ptr::write(
-            dest.add(USERNAME_OFFSET) as *mut [u8; COLUMN_USERNAME_SIZE],
-            source.username,
-        );
-        std::ptr::write(
-            dest.add(EMAIL_OFFSET) as *mut [u8; COLUMN_EMAIL_SIZE],
-            source.email,
-        );
-    }
+pub fn serialize_row(source: &Row, dest: &mut [u8]) {
+    write_u32(dest, ID_OFFSET, source.id);
+    dest[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE].copy_from_slice(&source.username);
+    dest[EMAIL_OFFSET..EMAIL_OFFSET + EMAIL_SIZE].copy_from_slice(&source.email);
 }
 
-pub fn deserialize_row(source: *const u8, dest: &mut Row) {
-    unsafe {
-        dest.id = std::ptr::read(source.add(ID_OFFSET) as *mut u32);
-        dest.username =
-            std::ptr::read(source.add(USERNAME_OFFSET) as *mut [u8; COLUMN_USERNAME_SIZE]);
-        dest.email = std::ptr::read(source.add(EMAIL_OFFSET) as *mut [u8; COLUMN_EMAIL_SIZE]);
-    }
+pub fn deserialize_row(source: &[u8], dest: &mut Row) {
+    dest.id = read_u32(source, ID_OFFSET);
+    dest.username
+        .copy_from_slice(&source[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE]);
+    dest.email
+        .copy_from_slice(&source[EMAIL_OFFSET..EMAIL_OFFSET + EMAIL_SIZE]);
 }
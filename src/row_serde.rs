@@ -0,0 +1,740 @@
+// Maps Rust types to table rows through serde. Struct fields and map keys are matched
+// to columns by name; tuples and sequences are matched to columns by position.
+
+use crate::error::{BindError, DbError, Result};
+use crate::row::COLUMN_NAMES;
+use crate::value::Value;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess};
+use serde::ser::{self, Impossible, Serialize};
+use std::fmt::Display;
+
+// Column values for `value`, in table column order
+pub fn to_values<T: Serialize + ?Sized>(value: &T) -> Result<Vec<Value>> {
+    let mut serializer = RowSerializer {
+        values: vec![None; COLUMN_NAMES.len()],
+        next_position: 0,
+    };
+    value.serialize(&mut serializer)?;
+
+    serializer
+        .values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            value.ok_or_else(|| DbError::Serde(format!("missing column {}", COLUMN_NAMES[i])))
+        })
+        .collect()
+}
+
+pub fn from_values<T: DeserializeOwned>(values: Vec<Value>) -> Result<T> {
+    T::deserialize(RowDeserializer { values })
+}
+
+fn column_index(name: &str) -> Result<usize> {
+    COLUMN_NAMES
+        .iter()
+        .position(|column| *column == name)
+        .ok_or_else(|| DbError::Serde(format!("no column named {}", name)))
+}
+
+fn mismatch(column: usize) -> DbError {
+    BindError::Mismatch {
+        column: COLUMN_NAMES[column],
+    }
+    .into()
+}
+
+fn unsupported(what: &str) -> DbError {
+    DbError::Serde(format!("{} can't be stored in a column", what))
+}
+
+impl ser::Error for DbError {
+    fn custom<T: Display>(msg: T) -> Self {
+        DbError::Serde(msg.to_string())
+    }
+}
+impl de::Error for DbError {
+    fn custom<T: Display>(msg: T) -> Self {
+        DbError::Serde(msg.to_string())
+    }
+}
+
+struct RowSerializer {
+    values: Vec<Option<Value>>,
+    // Column for the next tuple or sequence element
+    next_position: usize,
+}
+
+impl RowSerializer {
+    fn set(&mut self, index: usize, value: Value) -> Result<()> {
+        let slot = self
+            .values
+            .get_mut(index)
+            .ok_or_else(|| DbError::Serde(format!("expected {} columns", COLUMN_NAMES.len())))?;
+        *slot = Some(value);
+        Ok(())
+    }
+
+    fn set_next<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let value = value.serialize(ValueSerializer::column(self.next_position))?;
+        self.set(self.next_position, value)?;
+        self.next_position += 1;
+        Ok(())
+    }
+}
+
+macro_rules! row_unsupported {
+    ($($method:ident($($arg:ty),*)),* $(,)?) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> Result<()> {
+                Err(unsupported("a single value"))
+            }
+        )*
+    };
+}
+
+impl<'a> ser::Serializer for &'a mut RowSerializer {
+    type Ok = ();
+    type Error = DbError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Impossible<(), DbError>;
+    type SerializeMap = MapSerializer<'a>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), DbError>;
+
+    row_unsupported! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+        serialize_unit_variant(&'static str, u32, &'static str),
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<()> {
+        Err(unsupported("an enum"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(unsupported("an enum"))
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer<'a>> {
+        Ok(MapSerializer {
+            row: self,
+            key: None,
+        })
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(unsupported("an enum"))
+    }
+}
+
+impl ser::SerializeSeq for &mut RowSerializer {
+    type Ok = ();
+    type Error = DbError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.set_next(value)
+    }
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+impl ser::SerializeTuple for &mut RowSerializer {
+    type Ok = ();
+    type Error = DbError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.set_next(value)
+    }
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+impl ser::SerializeTupleStruct for &mut RowSerializer {
+    type Ok = ();
+    type Error = DbError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.set_next(value)
+    }
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+impl ser::SerializeStruct for &mut RowSerializer {
+    type Ok = ();
+    type Error = DbError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        let index = column_index(key)?;
+        let value = value.serialize(ValueSerializer::column(index))?;
+        self.set(index, value)
+    }
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct MapSerializer<'a> {
+    row: &'a mut RowSerializer,
+    key: Option<usize>,
+}
+impl ser::SerializeMap for MapSerializer<'_> {
+    type Ok = ();
+    type Error = DbError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        match key.serialize(ValueSerializer { column: None })? {
+            Value::Text(name) => self.key = Some(column_index(&name)?),
+            Value::Integer(_) => return Err(unsupported("a map with non-string keys")),
+        }
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let index = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        let value = value.serialize(ValueSerializer::column(index))?;
+        self.row.set(index, value)
+    }
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Serializes a single column value, or a map key when there is no column
+struct ValueSerializer {
+    column: Option<usize>,
+}
+impl ValueSerializer {
+    fn column(index: usize) -> Self {
+        Self {
+            column: Some(index),
+        }
+    }
+}
+
+macro_rules! serialize_integer {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method(self, value: $ty) -> Result<Value> {
+                Ok(Value::Integer(value.into()))
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = DbError;
+    type SerializeSeq = Impossible<Value, DbError>;
+    type SerializeTuple = Impossible<Value, DbError>;
+    type SerializeTupleStruct = Impossible<Value, DbError>;
+    type SerializeTupleVariant = Impossible<Value, DbError>;
+    type SerializeMap = Impossible<Value, DbError>;
+    type SerializeStruct = Impossible<Value, DbError>;
+    type SerializeStructVariant = Impossible<Value, DbError>;
+
+    serialize_integer! {
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+    }
+
+    fn serialize_bool(self, value: bool) -> Result<Value> {
+        Ok(Value::Integer(value as i64))
+    }
+    // Too large for any column, so the same mismatch as an id that doesn't fit
+    fn serialize_u64(self, value: u64) -> Result<Value> {
+        i64::try_from(value)
+            .map(Value::Integer)
+            .map_err(|_| match self.column {
+                Some(column) => mismatch(column),
+                None => DbError::Serde(format!("{} is too large for a column", value)),
+            })
+    }
+    fn serialize_f32(self, _value: f32) -> Result<Value> {
+        Err(unsupported("a float"))
+    }
+    fn serialize_f64(self, _value: f64) -> Result<Value> {
+        Err(unsupported("a float"))
+    }
+    fn serialize_char(self, value: char) -> Result<Value> {
+        Ok(Value::Text(value.to_string()))
+    }
+    fn serialize_str(self, value: &str) -> Result<Value> {
+        Ok(Value::Text(value.to_string()))
+    }
+    fn serialize_bytes(self, _value: &[u8]) -> Result<Value> {
+        Err(unsupported("a byte array"))
+    }
+    fn serialize_none(self) -> Result<Value> {
+        Err(unsupported("None"))
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Value> {
+        Err(unsupported("()"))
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value> {
+        Err(unsupported("a unit struct"))
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value> {
+        Ok(Value::Text(variant.to_string()))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Value> {
+        Err(unsupported("an enum with data"))
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(unsupported("a sequence"))
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(unsupported("a tuple"))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(unsupported("a tuple struct"))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(unsupported("an enum with data"))
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(unsupported("a map"))
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(unsupported("a struct"))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(unsupported("an enum with data"))
+    }
+}
+
+// Presents a row as a map from column name to value, or as a sequence of values
+struct RowDeserializer {
+    values: Vec<Value>,
+}
+
+impl<'de> de::Deserializer<'de> for RowDeserializer {
+    type Error = DbError;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(RowMapAccess {
+            columns: self.values.into_iter().enumerate(),
+            value: None,
+        })
+    }
+
+    fn deserialize_seq<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(RowSeqAccess {
+            columns: self.values.into_iter().enumerate(),
+        })
+    }
+    fn deserialize_tuple<V: de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_tuple_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct map struct enum identifier ignored_any
+    }
+}
+
+// Each column's position alongside its value
+type Columns = std::iter::Enumerate<std::vec::IntoIter<Value>>;
+
+// A value the target type can't take, such as text for an integer field or an
+// integer out of its range, is a mismatch for the column it came from
+fn deserialize_column<'de, T: DeserializeSeed<'de>>(
+    seed: T,
+    column: usize,
+    value: Value,
+) -> Result<T::Value> {
+    seed.deserialize(ValueDeserializer { value })
+        .map_err(|e| match e {
+            DbError::Serde(_) => mismatch(column),
+            e => e,
+        })
+}
+
+struct RowMapAccess {
+    columns: Columns,
+    value: Option<(usize, Value)>,
+}
+impl<'de> MapAccess<'de> for RowMapAccess {
+    type Error = DbError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.columns.next() {
+            Some((column, value)) => {
+                self.value = Some((column, value));
+                seed.deserialize(COLUMN_NAMES[column].into_deserializer())
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let (column, value) = self
+            .value
+            .take()
+            .expect("next_value_seed called before next_key_seed");
+        deserialize_column(seed, column, value)
+    }
+}
+
+struct RowSeqAccess {
+    columns: Columns,
+}
+impl<'de> SeqAccess<'de> for RowSeqAccess {
+    type Error = DbError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        match self.columns.next() {
+            Some((column, value)) => deserialize_column(seed, column, value).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+struct ValueDeserializer {
+    value: Value,
+}
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = DbError;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Integer(value) => visitor.visit_i64(value),
+            Value::Text(value) => visitor.visit_string(value),
+        }
+    }
+
+    // Columns are never NULL
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Integer(value) => visitor.visit_bool(value != 0),
+            Value::Text(value) => visitor.visit_string(value),
+        }
+    }
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.value {
+            Value::Text(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Value::Integer(value) => visitor.visit_i64(value),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statement::row_from_values;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct User {
+        id: u32,
+        username: String,
+        email: String,
+    }
+
+    fn user_values() -> Vec<Value> {
+        vec![
+            Value::Integer(1),
+            Value::Text("user1".to_string()),
+            Value::Text("person1@example.com".to_string()),
+        ]
+    }
+
+    fn assert_mismatch<T>(result: Result<T>, expected: &str) {
+        match result {
+            Err(DbError::Bind(BindError::Mismatch { column })) => assert_eq!(column, expected),
+            Err(e) => panic!("expected a mismatch for {}, got {:?}", expected, e),
+            Ok(_) => panic!("expected a mismatch for {}", expected),
+        }
+    }
+
+    fn assert_serde_error<T>(result: Result<T>, expected: &str) {
+        match result {
+            Err(DbError::Serde(msg)) => assert!(msg.contains(expected), "{}", msg),
+            Err(e) => panic!("expected a serde error, got {:?}", e),
+            Ok(_) => panic!("expected a serde error containing {}", expected),
+        }
+    }
+
+    #[test]
+    fn round_trips_a_struct() {
+        let user = User {
+            id: 1,
+            username: "user1".to_string(),
+            email: "person1@example.com".to_string(),
+        };
+        let values = to_values(&user).unwrap();
+        assert_eq!(values, user_values());
+        assert_eq!(from_values::<User>(values).unwrap(), user);
+    }
+
+    #[test]
+    fn round_trips_a_tuple() {
+        let user = (1u32, "user1", "person1@example.com");
+        let values = to_values(&user).unwrap();
+        assert_eq!(values, user_values());
+        let (id, username, email): (u32, String, String) = from_values(values).unwrap();
+        assert_eq!((id, username.as_str(), email.as_str()), user);
+    }
+
+    #[test]
+    fn matches_struct_fields_by_name_in_any_order() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Reordered {
+            email: String,
+            id: u32,
+            username: String,
+        }
+
+        let user = Reordered {
+            email: "person1@example.com".to_string(),
+            id: 1,
+            username: "user1".to_string(),
+        };
+        let values = to_values(&user).unwrap();
+        assert_eq!(values, user_values());
+        assert_eq!(from_values::<Reordered>(values).unwrap(), user);
+    }
+
+    #[test]
+    fn rejects_unknown_and_missing_columns() {
+        #[derive(Serialize, Deserialize, Debug)]
+        struct WithAge {
+            id: u32,
+            username: String,
+            email: String,
+            age: u32,
+        }
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct IdOnly {
+            id: u32,
+        }
+
+        let with_age = WithAge {
+            id: 1,
+            username: "user1".to_string(),
+            email: "person1@example.com".to_string(),
+            age: 30,
+        };
+        assert_serde_error(to_values(&with_age), "no column named age");
+        assert_serde_error(from_values::<WithAge>(user_values()), "age");
+
+        assert_serde_error(to_values(&IdOnly { id: 1 }), "missing column username");
+        assert_serde_error(to_values(&(1u32, "user1")), "missing column email");
+        assert_serde_error(
+            to_values(&(1u32, "user1", "person1@example.com", "extra")),
+            "expected 3 columns",
+        );
+
+        // Columns the struct doesn't ask for are skipped
+        assert_eq!(
+            from_values::<IdOnly>(user_values()).unwrap(),
+            IdOnly { id: 1 }
+        );
+    }
+
+    #[test]
+    fn handles_option_fields() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct MaybeUser {
+            id: u32,
+            username: Option<String>,
+            email: String,
+            #[serde(skip_serializing)]
+            nickname: Option<String>,
+        }
+
+        let user = MaybeUser {
+            id: 1,
+            username: Some("user1".to_string()),
+            email: "person1@example.com".to_string(),
+            nickname: None,
+        };
+        let values = to_values(&user).unwrap();
+        assert_eq!(values, user_values());
+        // Every column has a value, and a field with no column is left out
+        assert_eq!(from_values::<MaybeUser>(values).unwrap(), user);
+
+        let no_username = MaybeUser {
+            username: None,
+            ..user
+        };
+        assert_serde_error(to_values(&no_username), "None can't be stored");
+    }
+
+    #[test]
+    fn rejects_integers_that_overflow_the_id() {
+        assert_mismatch(to_values(&(u64::MAX, "user1", "person1@example.com")), "id");
+
+        let values = to_values(&(1i64 << 40, "user1", "person1@example.com")).unwrap();
+        assert_mismatch(row_from_values(&values[0], &values[1], &values[2]), "id");
+
+        let mut values = user_values();
+        values[0] = Value::Integer(300);
+        assert_mismatch(from_values::<(u8, String, String)>(values), "id");
+    }
+
+    #[test]
+    fn reports_type_mismatches_by_column() {
+        #[derive(Deserialize, Debug)]
+        struct TextId {
+            #[allow(dead_code)]
+            id: String,
+        }
+        #[derive(Deserialize, Debug)]
+        struct NumericName {
+            #[allow(dead_code)]
+            username: u32,
+        }
+
+        assert_mismatch(from_values::<TextId>(user_values()), "id");
+        assert_mismatch(from_values::<NumericName>(user_values()), "username");
+        assert_mismatch(from_values::<(u32, String, u32)>(user_values()), "email");
+
+        let values = to_values(&("one", "user1", "person1@example.com")).unwrap();
+        assert_mismatch(row_from_values(&values[0], &values[1], &values[2]), "id");
+        let values = to_values(&(1u32, 2u32, "person1@example.com")).unwrap();
+        assert_mismatch(
+            row_from_values(&values[0], &values[1], &values[2]),
+            "username",
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use crate::checksum::checksum_matches;
+use crate::error::{DbError, Result};
+use crate::node::leaf_node::LeafNode;
+use crate::node::{get_node_type, NodeType};
+use crate::page::PageRef;
+use crate::pager::{read_page, Pager, MAX_PAGE_SIZE, MIN_PAGE_SIZE};
+use crate::row::{deserialize_row, Row};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+// The rows that could be read back out of a damaged file
+pub struct Salvage {
+    // Sorted by id, with each id once
+    pub rows: Vec<Row>,
+    pub leaf_pages: u32,
+    // Pages that failed their checksum or hold a node that can't be read
+    pub unreadable_pages: Vec<u32>,
+}
+
+#[derive(Default)]
+struct Salvager {
+    rows: BTreeMap<u32, Row>,
+    leaf_pages: u32,
+    unreadable_pages: Vec<u32>,
+}
+
+impl Salvager {
+    fn read(&mut self, page_num: u32, page: Result<PageRef<'_>>) -> Result<()> {
+        let page = match page {
+            Ok(page) => page,
+            Err(DbError::Corrupt { .. }) => {
+                self.unreadable_pages.push(page_num);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        match get_node_type(&page) {
+            Ok(NodeType::Leaf) => {}
+            Ok(NodeType::Internal) => return Ok(()),
+            Err(_) => {
+                self.unreadable_pages.push(page_num);
+                return Ok(());
+            }
+        }
+        let leaf = LeafNode::new(page);
+        let num_cells = leaf.get_num_cells();
+
+        self.leaf_pages += 1;
+        for cell_num in 0..num_cells {
+            let mut row = Row::new();
+            deserialize_row(leaf.value(cell_num), &mut row);
+            // A cell whose key and row disagree can't be trusted
+            if row.id == leaf.get_key(cell_num) {
+                self.rows.entry(row.id).or_insert(row);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Salvage {
+        Salvage {
+            rows: self.rows.into_values().collect(),
+            leaf_pages: self.leaf_pages,
+            unreadable_pages: self.unreadable_pages,
+        }
+    }
+}
+
+// Reads every leaf in the file, whether or not the tree still reaches it, so rows
+// outlive damage to the nodes above them. Rows are never changed once inserted, so
+// a stale copy of a leaf holds nothing the current one doesn't.
+pub fn salvage_rows(pager: &mut Pager) -> Result<Salvage> {
+    let mut salvager = Salvager::default();
+    for page_num in 1..pager.num_pages() {
+        salvager.read(page_num, pager.page(page_num))?;
+    }
+    Ok(salvager.finish())
+}
+
+// The page size of a file whose header can't be read, found by trying each valid
+// size and keeping the one whose pages most often pass their checksums. Only files
+// with checksums can be measured this way.
+pub fn guess_page_size(file: &File) -> Result<Option<u32>> {
+    let file_size = file.metadata()?.len();
+    let mut best = None;
+    let mut best_matches = 0;
+
+    let mut page_size = MIN_PAGE_SIZE;
+    while page_size <= MAX_PAGE_SIZE {
+        let mut page = vec![0; page_size as usize];
+        let mut matches = 0;
+        // Page 0 holds the damaged header
+        let mut offset = page_size as u64;
+        while offset + page_size as u64 <= file_size {
+            file.read_exact_at(&mut page, offset)?;
+            if checksum_matches(&page) {
+                matches += 1;
+            }
+            offset += page_size as u64;
+        }
+        if matches > best_matches {
+            best = Some(page_size);
+            best_matches = matches;
+        }
+        page_size *= 2;
+    }
+    Ok(best)
+}
+
+// Like `salvage_rows`, for a file read without a pager because its header is gone
+pub fn salvage_file_rows(file: &File, page_size: u32) -> Result<Salvage> {
+    let num_pages = file.metadata()?.len().div_ceil(page_size as u64) as u32;
+    let mut salvager = Salvager::default();
+    for page_num in 1..num_pages {
+        match read_page(Some(file), page_size, true, page_num) {
+            Ok(data) => salvager.read(page_num, Ok(PageRef::new(page_num, &data)))?,
+            Err(e) => salvager.read(page_num, Err(e))?,
+        }
+    }
+    Ok(salvager.finish())
+}
@@ -0,0 +1,91 @@
+use crate::error::{DbError, Result};
+use crate::page::PageRef;
+use crate::pager::{read_page, TABLE_MAX_PAGES};
+use std::fs::File;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+
+// One version of a page. Pages nothing has changed since they were read from the
+// file share their slot between snapshots, and it is filled in on first use.
+type Slot = Arc<OnceLock<Box<[u8]>>>;
+
+// The database as of one commit. A query reads a single snapshot from start to
+// finish, so it sees every insert committed before it began and none of those
+// committed while it runs, and it never waits for a writer.
+pub struct Snapshot {
+    file: Option<Arc<File>>,
+    page_size: u32,
+    checksums: bool,
+    pages: Vec<Slot>,
+}
+
+impl Snapshot {
+    fn new(file: Option<Arc<File>>, page_size: u32, checksums: bool) -> Self {
+        Self {
+            file,
+            page_size,
+            checksums,
+            pages: (0..TABLE_MAX_PAGES).map(|_| Slot::default()).collect(),
+        }
+    }
+
+    pub fn page(&self, page_num: u32) -> Result<PageRef<'_>> {
+        let slot = self
+            .pages
+            .get(page_num as usize)
+            .ok_or(DbError::Corrupt { page: page_num })?;
+        if slot.get().is_none() {
+            // Another query may fill the slot first, but only with the same bytes
+            let page = read_page(
+                self.file.as_deref(),
+                self.page_size,
+                self.checksums,
+                page_num,
+            )?;
+            let _ = slot.set(page);
+        }
+        Ok(PageRef::new(page_num, slot.get().unwrap()))
+    }
+}
+
+// The latest snapshot. Each commit replaces it with a copy holding the new versions
+// of the pages it changed, and older snapshots live on until the last query
+// reading them finishes.
+pub struct Versions {
+    latest: Mutex<Arc<Snapshot>>,
+}
+
+impl Versions {
+    pub fn new(file: Option<Arc<File>>, page_size: u32, checksums: bool) -> Self {
+        Self {
+            latest: Mutex::new(Arc::new(Snapshot::new(file, page_size, checksums))),
+        }
+    }
+
+    pub fn latest(&self) -> Arc<Snapshot> {
+        self.lock().clone()
+    }
+
+    // Starts again from the file as it is now, once the pager has reloaded it
+    pub fn reset(&self, file: Option<Arc<File>>, page_size: u32, checksums: bool) {
+        *self.lock() = Arc::new(Snapshot::new(file, page_size, checksums));
+    }
+
+    pub fn publish(&self, changed: impl IntoIterator<Item = (u32, Box<[u8]>)>) {
+        let mut latest = self.lock();
+        let mut pages = latest.pages.clone();
+        for (page_num, page) in changed {
+            pages[page_num as usize] = Arc::new(OnceLock::from(page));
+        }
+        *latest = Arc::new(Snapshot {
+            file: latest.file.clone(),
+            page_size: latest.page_size,
+            checksums: latest.checksums,
+            pages,
+        });
+    }
+
+    // Snapshots are only ever swapped whole, so poisoning is ignored
+    fn lock(&self) -> MutexGuard<'_, Arc<Snapshot>> {
+        self.latest.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
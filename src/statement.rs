@@ -1,104 +1,297 @@
-use crate::row::{Row, COLUMN_EMAIL_SIZE, COLUMN_USERNAME_SIZE};
-use crate::table::Table;
+use crate::error::{BindError, DbError, Result, SyntaxError};
+use crate::header::AutoVacuum;
+use crate::row::{Row, COLUMN_EMAIL_SIZE, COLUMN_NAMES, COLUMN_USERNAME_SIZE};
+use crate::value::Value;
+
+// Parameter numbers above this are almost certainly typos
+const MAX_PARAMETER_INDEX: usize = 999;
 
 pub enum Statement {
-    Insert(Row),
+    Insert([Operand; 3]),
     Select,
+    Savepoint(String),
+    Release(String),
+    RollbackTo(String),
+    IntegrityCheck,
+    AutoVacuum(AutoVacuum),
+    IncrementalVacuum(Option<u32>),
+    Vacuum,
+    VacuumInto(String),
 }
 impl Statement {
-    pub fn new(args: &Vec<&str>) -> Result<Self, PrepareResult> {
+    pub fn new(args: &[&str], params: &mut Parameters) -> Result<Self> {
         let command = args[0];
         match command {
-            "insert" => Statement::new_insert(args),
+            "insert" => Statement::new_insert(args, params),
             "select" => Statement::new_select(args),
-            _ => Err(PrepareResult::UnrecognizedCommand),
+            "savepoint" => Statement::new_savepoint(args),
+            "release" => Statement::new_release(args),
+            "rollback" => Statement::new_rollback_to(args),
+            "pragma" => Statement::new_pragma(args),
+            "vacuum" => Statement::new_vacuum(args),
+            _ => Err(SyntaxError::UnrecognizedKeyword(args.join(" ")).into()),
         }
     }
-    fn new_insert(args: &Vec<&str>) -> Result<Self, PrepareResult> {
+    fn new_insert(args: &[&str], params: &mut Parameters) -> Result<Self> {
         if args.len() < 4 {
-            return Err(PrepareResult::SyntaxError);
+            return Err(SyntaxError::CouldNotParse.into());
         }
 
-        let mut row = Row::new();
-        let id = args[1].parse::<i32>().unwrap();
-        if id < 0 {
-            return Err(PrepareResult::NegativeId);
+        let id = match Operand::parse(args[1], params)? {
+            Operand::Literal(Value::Text(id)) => Operand::Literal(Value::Integer(parse_id(&id)?)),
+            operand => operand,
+        };
+        let username = Operand::parse(args[2], params)?;
+        let email = Operand::parse(args[3], params)?;
+        Ok(Statement::Insert([id, username, email]))
+    }
+
+    fn new_select(args: &[&str]) -> Result<Self> {
+        if args.is_empty() {
+            return Err(SyntaxError::CouldNotParse.into());
         }
-        row.id = id as u32;
 
-        let username_len = args[2].as_bytes().len();
-        if username_len > COLUMN_USERNAME_SIZE {
-            return Err(PrepareResult::StringTooLong);
+        Ok(Statement::Select)
+    }
+
+    // savepoint name
+    fn new_savepoint(args: &[&str]) -> Result<Self> {
+        match args {
+            [_, name] => Ok(Statement::Savepoint(name.to_string())),
+            _ => Err(SyntaxError::CouldNotParse.into()),
         }
-        row.username[..username_len].copy_from_slice(args[2].as_bytes());
+    }
 
-        let email_len = args[3].as_bytes().len();
-        if email_len > COLUMN_EMAIL_SIZE {
-            return Err(PrepareResult::StringTooLong);
+    // release [savepoint] name
+    fn new_release(args: &[&str]) -> Result<Self> {
+        match args {
+            [_, name] | [_, "savepoint", name] => Ok(Statement::Release(name.to_string())),
+            _ => Err(SyntaxError::CouldNotParse.into()),
         }
-        row.email[..email_len].copy_from_slice(args[3].as_bytes());
+    }
 
-        Ok(Statement::Insert(row))
+    // pragma integrity_check | auto_vacuum=mode | incremental_vacuum[(n)]
+    fn new_pragma(args: &[&str]) -> Result<Self> {
+        let [_, pragma] = args else {
+            return Err(SyntaxError::CouldNotParse.into());
+        };
+        if *pragma == "integrity_check" {
+            return Ok(Statement::IntegrityCheck);
+        }
+        if let Some(mode) = pragma.strip_prefix("auto_vacuum=") {
+            let mode = AutoVacuum::parse(mode).ok_or(SyntaxError::CouldNotParse)?;
+            return Ok(Statement::AutoVacuum(mode));
+        }
+        match pragma.strip_prefix("incremental_vacuum") {
+            Some("") => Ok(Statement::IncrementalVacuum(None)),
+            Some(count) => {
+                let count = count
+                    .strip_prefix('(')
+                    .and_then(|count| count.strip_suffix(')'))
+                    .and_then(|count| count.parse().ok())
+                    .ok_or(SyntaxError::CouldNotParse)?;
+                Ok(Statement::IncrementalVacuum(Some(count)))
+            }
+            None => Err(SyntaxError::CouldNotParse.into()),
+        }
     }
 
-    fn new_select(args: &Vec<&str>) -> Result<Self, PrepareResult> {
-        if args.len() < 1 {
-            return Err(PrepareResult::SyntaxError);
+    // vacuum [into 'path']
+    fn new_vacuum(args: &[&str]) -> Result<Self> {
+        match args {
+            [_] => Ok(Statement::Vacuum),
+            [_, "into", path] => {
+                let path = path
+                    .strip_prefix('\'')
+                    .and_then(|path| path.strip_suffix('\''))
+                    .filter(|path| !path.is_empty())
+                    .ok_or(SyntaxError::CouldNotParse)?;
+                Ok(Statement::VacuumInto(path.to_string()))
+            }
+            _ => Err(SyntaxError::CouldNotParse.into()),
         }
+    }
 
-        Ok(Statement::Select)
+    // rollback to [savepoint] name
+    fn new_rollback_to(args: &[&str]) -> Result<Self> {
+        match args {
+            [_, "to", name] | [_, "to", "savepoint", name] => {
+                Ok(Statement::RollbackTo(name.to_string()))
+            }
+            _ => Err(SyntaxError::CouldNotParse.into()),
+        }
     }
 }
 
-pub enum PrepareResult {
-    Success(Statement),
-    UnrecognizedCommand,
-    SyntaxError,
-    StringTooLong,
-    NegativeId,
+// A value in the statement text: either written out, or a placeholder for the
+// parameter with the given 1-based index that is bound when the statement runs
+pub enum Operand {
+    Literal(Value),
+    Parameter(usize),
 }
-impl PrepareResult {
-    pub fn err_msg(&self, input: &String) -> String {
+impl Operand {
+    // `?` takes the next unused index, `?NNN` names an index explicitly and `:name`
+    // shares one index between every use of the same name
+    fn parse(token: &str, params: &mut Parameters) -> Result<Self> {
+        if token == "?" {
+            return Ok(Operand::Parameter(params.add_anonymous()));
+        }
+        if let Some(index) = token.strip_prefix('?') {
+            let index = index
+                .parse::<usize>()
+                .ok()
+                .filter(|index| (1..=MAX_PARAMETER_INDEX).contains(index))
+                .ok_or(SyntaxError::CouldNotParse)?;
+            return Ok(Operand::Parameter(params.add_numbered(index)));
+        }
+        if token.len() > 1 && token.starts_with(':') {
+            return Ok(Operand::Parameter(params.add_named(token)));
+        }
+
+        Ok(Operand::Literal(Value::Text(token.to_string())))
+    }
+
+    fn bind<'a>(&'a self, values: &'a [Value]) -> &'a Value {
         match self {
-            PrepareResult::Success(_) => {
-                panic!("invalid call")
-            }
-            PrepareResult::UnrecognizedCommand => {
-                format!("Unrecognized keyword at start of '{}'", input)
+            Operand::Literal(value) => value,
+            Operand::Parameter(index) => &values[index - 1],
+        }
+    }
+}
+
+// The placeholders of a prepared statement. Slot `i` holds the name of parameter
+// `i + 1`, if it has one.
+#[derive(Default)]
+pub struct Parameters {
+    names: Vec<Option<String>>,
+}
+impl Parameters {
+    pub fn count(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .position(|slot| slot.as_deref() == Some(name))
+            .map(|i| i + 1)
+    }
+
+    fn add_anonymous(&mut self) -> usize {
+        self.names.push(None);
+        self.names.len()
+    }
+    fn add_numbered(&mut self, index: usize) -> usize {
+        if index > self.names.len() {
+            self.names.resize(index, None);
+        }
+        index
+    }
+    fn add_named(&mut self, name: &str) -> usize {
+        if let Some(index) = self.index(name) {
+            return index;
+        }
+        self.names.push(Some(name.to_string()));
+        self.names.len()
+    }
+
+    // Values for every parameter, in index order
+    pub fn bind(&self, values: &[Value]) -> Result<Vec<Value>> {
+        if values.len() != self.count() {
+            return Err(BindError::ParameterCount {
+                expected: self.count(),
+                given: values.len(),
             }
-            PrepareResult::SyntaxError => String::from("Syntax error. Could not parse statement"),
-            PrepareResult::StringTooLong => String::from("String is too long."),
-            PrepareResult::NegativeId => String::from("ID must be positive."),
+            .into());
         }
+        Ok(values.to_vec())
+    }
+
+    pub fn bind_named(&self, values: &[(&str, Value)]) -> Result<Vec<Value>> {
+        let mut bound = vec![None; self.count()];
+        for (name, value) in values {
+            let index = self
+                .index(name)
+                .ok_or_else(|| BindError::UnknownName(name.to_string()))?;
+            bound[index - 1] = Some(value.clone());
+        }
+
+        bound
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| value.ok_or(BindError::Unbound(i + 1).into()))
+            .collect()
     }
 }
 
-pub fn prepare_statement(buffer: &String) -> PrepareResult {
-    let args: Vec<&str> = buffer.split(' ').collect();
-    match Statement::new(&args) {
-        Ok(statement) => PrepareResult::Success(statement),
-        Err(err) => err,
+// Builds the row an insert writes, with placeholders replaced by `values`
+pub fn bind_row(operands: &[Operand; 3], values: &[Value]) -> Result<Row> {
+    let [id, username, email] = operands;
+    row_from_values(id.bind(values), username.bind(values), email.bind(values))
+}
+
+// The rows `pragma integrity_check` returns: one per problem, or a single "ok"
+pub fn integrity_check_rows(problems: Vec<String>) -> Vec<Vec<Value>> {
+    if problems.is_empty() {
+        return vec![vec![Value::Text("ok".to_string())]];
     }
+    problems
+        .into_iter()
+        .map(|problem| vec![Value::Text(problem)])
+        .collect()
 }
 
-pub enum ExecuteResult {
-    Success,
-    TableFull,
-    DuplicateKey,
+pub fn prepare_row(id: &str, username: &str, email: &str) -> Result<Row> {
+    let id = Value::Integer(parse_id(id)?);
+    row_from_values(&id, &username.into(), &email.into())
 }
-impl ExecuteResult {
-    pub fn msg(&self) -> &str {
-        match self {
-            ExecuteResult::Success => "Executed.",
-            ExecuteResult::TableFull => "Error: Table full.",
-            ExecuteResult::DuplicateKey => "Error: Duplicated key.",
-        }
+
+fn parse_id(id: &str) -> Result<i64> {
+    let id = id.parse::<i32>().map_err(|_| SyntaxError::CouldNotParse)?;
+    Ok(id as i64)
+}
+
+pub fn row_from_values(id: &Value, username: &Value, email: &Value) -> Result<Row> {
+    let mut row = Row::new();
+    let Value::Integer(id) = *id else {
+        return Err(mismatch(0));
+    };
+    if id < 0 {
+        return Err(SyntaxError::NegativeId.into());
+    }
+    row.id = u32::try_from(id).map_err(|_| mismatch(0))?;
+
+    let Value::Text(username) = username else {
+        return Err(mismatch(1));
+    };
+    let username_len = username.len();
+    if username_len > COLUMN_USERNAME_SIZE {
+        return Err(SyntaxError::StringTooLong.into());
+    }
+    row.username[..username_len].copy_from_slice(username.as_bytes());
+
+    let Value::Text(email) = email else {
+        return Err(mismatch(2));
+    };
+    let email_len = email.len();
+    if email_len > COLUMN_EMAIL_SIZE {
+        return Err(SyntaxError::StringTooLong.into());
     }
+    row.email[..email_len].copy_from_slice(email.as_bytes());
+
+    Ok(row)
 }
 
-pub fn execute_statement(statement: Statement, table: &mut Table) -> ExecuteResult {
-    match statement {
-        Statement::Insert(row) => table.insert(row),
-        Statement::Select => table.select(),
+fn mismatch(column: usize) -> DbError {
+    BindError::Mismatch {
+        column: COLUMN_NAMES[column],
     }
+    .into()
+}
+
+pub fn prepare_statement(buffer: &str) -> Result<(Statement, Parameters)> {
+    let args: Vec<&str> = buffer.split(' ').collect();
+    let mut params = Parameters::default();
+    let statement = Statement::new(&args, &mut params)?;
+    Ok((statement, params))
 }
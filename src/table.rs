@@ -1,88 +1,323 @@
-use crate::cursor::{table_find, table_start};
+use crate::bulk_load::bulk_load;
+use crate::cursor::{latch_leaf_for_insert, table_find, table_start};
+use crate::error::{ConstraintError, DbError, Result};
+use crate::header::{AutoVacuum, StorageMode};
+use crate::node::copy_on_write::{copy_on_write_insert, relocate_pages};
+use crate::node::integrity_check::integrity_check;
 use crate::node::leaf_node::{leaf_node_insert, LeafNode};
-use crate::node::{print_tree, NodeTrait};
+use crate::node::{print_tree, space_usage, NodeTrait, SpaceUsage};
 use crate::pager::Pager;
-use crate::row::{deserialize_row, Row};
-use crate::statement::ExecuteResult;
+use crate::row::Row;
 
 pub const INVALID_PAGE_NUM: u32 = u32::MAX;
+// Page 0 is the file header, so the tree starts on page 1
+pub const ROOT_PAGE_NUM: u32 = 1;
 
 pub struct Table {
     root_page_num: u32,
     pager: Option<Pager>,
 }
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Table {
     pub fn new() -> Self {
         Self {
-            root_page_num: 0,
+            root_page_num: ROOT_PAGE_NUM,
             pager: None,
         }
     }
-    pub fn db_open(&mut self, filename: &String) {
-        let mut pager = Pager::open(filename);
-        self.root_page_num = 0;
+    pub fn db_open(
+        &mut self,
+        filename: &str,
+        page_size: u32,
+        storage_mode: StorageMode,
+        read_only: bool,
+    ) -> Result<()> {
+        let pager = Pager::open(filename, page_size, storage_mode, read_only)?;
+        self.root_page_num = ROOT_PAGE_NUM;
+        self.pager = Some(pager);
+        // An in-memory database is never reloaded, so it needs its root page now
+        self.init_root()
+    }
 
-        if pager.num_pages() == 0 {
-            // New database file. Initialize page 0 as leaf node.
-            let root_node = pager.page(0);
-            let root_node = LeafNode::new(root_node);
-            unsafe {
-                root_node.initialize();
-                root_node.set_root(true);
-            }
+    // The pager keeps its cache if closing fails, so it can be retried
+    pub fn db_close(&mut self) -> Result<()> {
+        if self.pager.is_none() {
+            return Ok(());
         }
-        self.pager = Some(pager);
+        if self.auto_vacuum_on_close()? {
+            relocate_pages(self, u32::MAX)?;
+        }
+        self.pager().close()?;
+        self.pager = None;
+        Ok(())
     }
 
-    pub fn db_close(&mut self) {
-        match &mut self.pager {
-            Some(p) => p.close(),
-            None => {}
+    // Only a copy-on-write tree with changes to write back has pages to move
+    fn auto_vacuum_on_close(&mut self) -> Result<bool> {
+        let pager = self.pager();
+        Ok(pager.is_writing()
+            && pager.storage_mode() == StorageMode::CopyOnWrite
+            && pager.header()?.auto_vacuum == AutoVacuum::Full)
+    }
+
+    // Every statement reads under a shared lock, which may reload the cache
+    pub fn begin_read(&mut self) -> Result<()> {
+        self.pager().lock_shared()?;
+        self.init_root()
+    }
+
+    pub fn end_read(&mut self) -> Result<()> {
+        self.pager().unlock_shared()
+    }
+
+    pub fn begin_write(&mut self) -> Result<()> {
+        if self.pager().is_read_only() {
+            return Err(DbError::ReadOnly);
         }
+        self.pager().lock_reserved()?;
+        self.init_root()
     }
 
-    pub fn insert(&mut self, row: Row) -> ExecuteResult {
-        let root_page_num = self.root_page_num;
-        let root_page = self.pager().page(root_page_num);
-        let root_node = LeafNode::new(root_page);
+    // The root moves with every change to a copy-on-write tree, so it is read from
+    // the header whenever the cache may have been reloaded
+    fn init_root(&mut self) -> Result<()> {
+        let pager = self.pager();
+        let root_page_num = pager.header()?.root_page_num;
+        self.root_page_num = root_page_num;
+        let pager = self.pager();
+        if pager.num_pages() <= root_page_num {
+            // New database file. Initialize the root page as leaf node.
+            let mut root_node = LeafNode::new(pager.page_mut(root_page_num)?);
+            root_node.initialize();
+            root_node.set_root(true);
+        }
+        Ok(())
+    }
 
+    pub fn insert(&mut self, row: Row) -> Result<()> {
+        self.begin_write()?;
+        if self.pager().storage_mode() == StorageMode::CopyOnWrite {
+            return copy_on_write_insert(self, &row);
+        }
         let key_to_insert = row.id;
-        let mut cursor = table_find(self, key_to_insert);
+        let mut cursor = table_find(self, key_to_insert)?;
+        let cell_num = cursor.cell_num();
+        let leaf_node = cursor.leaf_node()?;
 
-        let num_cells = root_node.get_num_cells();
-        if cursor.cell_num() < num_cells {
-            let key_at_index = root_node.get_key(cursor.cell_num());
+        let num_cells = leaf_node.get_num_cells();
+        if cell_num < num_cells {
+            let key_at_index = leaf_node.get_key(cell_num);
             if key_at_index == key_to_insert {
-                return ExecuteResult::DuplicateKey;
+                return Err(ConstraintError::DuplicateKey.into());
             }
         }
+        drop(leaf_node);
 
         leaf_node_insert(&mut cursor, row.id, &row)
     }
 
-    pub fn select(&mut self) -> ExecuteResult {
-        let mut row = Row::new();
-        let mut cursor = table_start(self);
+    // Inserts into the row's leaf without changing the shape of the tree, which only
+    // needs a shared borrow, so inserts into different leaves can run in parallel.
+    // Returns false, having changed nothing, if the leaf is full and must be split.
+    // The caller must have called `begin_write`. The insert is committed as soon
+    // as it is made.
+    pub fn try_insert_in_leaf(&self, row: &Row) -> Result<bool> {
+        let pager = self.pager_ref();
+        if pager.storage_mode() == StorageMode::CopyOnWrite {
+            // Every copy-on-write insert changes the root
+            return Ok(false);
+        }
+        if pager.in_savepoint() {
+            // The leaf's before-image has to be recorded first
+            return Ok(false);
+        }
+        let max_cells = pager.layout().leaf_node_max_cells as u32;
+        let mut leaf = latch_leaf_for_insert(pager, self.root_page_num, row.id)?;
 
-        while !cursor.end_of_table() {
-            deserialize_row(cursor.value(), &mut row);
+        let num_cells = leaf.get_num_cells();
+        let cell_num = leaf.find_cell(row.id);
+        if cell_num < num_cells && leaf.get_key(cell_num) == row.id {
+            return Err(ConstraintError::DuplicateKey.into());
+        }
+        if num_cells >= max_cells {
+            return Ok(false);
+        }
+
+        leaf.insert_cell(cell_num, row.id, row);
+        pager.commit_page(leaf.page());
+        Ok(true)
+    }
+
+    pub fn bulk_load(&mut self, rows: &[Row], fill_factor: f64) -> Result<()> {
+        self.begin_write()?;
+        bulk_load(self, rows, fill_factor)
+    }
+
+    // Savepoints only record pages as they are changed, so setting and releasing
+    // them takes no lock. Changes made under them are written back at close as usual.
+    pub fn savepoint(&mut self, name: &str) {
+        self.pager().savepoint(name);
+    }
+
+    pub fn release(&mut self, name: &str) -> Result<()> {
+        self.pager().release(name)
+    }
 
-            println!("{}", row);
-            cursor.advance();
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        self.pager().rollback_to(name)?;
+        // A copy-on-write rollback puts back the header with the old root
+        self.init_root()
+    }
+
+    // Rebuilds the tree densely in a new file, which then replaces this one. Changes
+    // not yet written back go into the new file with everything else.
+    pub fn vacuum(&mut self) -> Result<()> {
+        if self.pager().in_savepoint() {
+            return Err(DbError::VacuumInSavepoint);
+        }
+        if self.pager().is_in_memory() {
+            // Never written to a file, so there is nothing to shrink
+            return Ok(());
+        }
+        self.begin_write()?;
+        let rows = self.rows()?;
+
+        let temp_path = format!("{}-vacuum", self.pager().path());
+        let _ = std::fs::remove_file(&temp_path);
+        if let Err(e) = self.write_compacted(&temp_path, &rows) {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        if let Err(e) = self.pager().replace_file(&temp_path) {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(e);
         }
+        self.begin_read()?;
+        self.end_read()
+    }
 
-        ExecuteResult::Success
+    // Takes effect when changes are next written back
+    pub fn set_auto_vacuum(&mut self, auto_vacuum: AutoVacuum) -> Result<()> {
+        self.begin_write()?;
+        let pager = self.pager();
+        let mut header = pager.header()?;
+        header.auto_vacuum = auto_vacuum;
+        pager.write_header(&header)
     }
 
-    pub fn print(&mut self) {
-        print_tree(self.pager(), 0, 0);
+    // Moves up to `max_pages` pages, or as many as it can, from the end of the file
+    // into free pages nearer the start. The free pages this leaves at the end are
+    // cut off when changes are written back. Does nothing unless auto-vacuum is
+    // incremental.
+    pub fn incremental_vacuum(&mut self, max_pages: Option<u32>) -> Result<()> {
+        let header = self.with_read_lock(|pager| pager.header())?;
+        if header.auto_vacuum != AutoVacuum::Incremental
+            || header.storage_mode != StorageMode::CopyOnWrite
+        {
+            return Ok(());
+        }
+        self.begin_write()?;
+        relocate_pages(self, max_pages.unwrap_or(u32::MAX))?;
+        Ok(())
+    }
+
+    // Writes a compacted copy of the database to a new file at `path`
+    pub fn vacuum_into(&mut self, path: &str) -> Result<()> {
+        if std::path::Path::new(path).exists() {
+            let message = format!("{} already exists", path);
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, message).into());
+        }
+        self.begin_read()?;
+        let result = self
+            .rows()
+            .and_then(|rows| self.write_compacted(path, &rows));
+        self.end_read()?;
+        result
+    }
+
+    // Every row in key order, read through the cache so changes not yet written
+    // back are included
+    fn rows(&self) -> Result<Vec<Row>> {
+        let mut rows = Vec::new();
+        let mut cursor = table_start(self)?;
+        while !cursor.end_of_table() {
+            rows.push(cursor.row()?);
+            cursor.advance()?;
+        }
+        Ok(rows)
+    }
+
+    // Full leaves waste no space, and nothing will be inserted into the copy until
+    // it is opened again. The copy keeps this file's auto-vacuum setting.
+    fn write_compacted(&mut self, path: &str, rows: &[Row]) -> Result<()> {
+        let pager = self.pager();
+        let auto_vacuum = pager.header()?.auto_vacuum;
+        let mut copy = Table::new();
+        copy.db_open(path, pager.page_size(), pager.storage_mode(), false)?;
+        let loaded = copy
+            .set_auto_vacuum(auto_vacuum)
+            .and_then(|()| copy.bulk_load(rows, 1.0));
+        copy.db_close()?;
+        loaded
+    }
+
+    // The root is read once the lock is held, since taking it may reload a tree
+    // another connection has moved
+    pub fn print(&mut self) -> Result<()> {
+        self.with_read_lock(|pager| {
+            let root_page_num = pager.header()?.root_page_num;
+            print_tree(pager, root_page_num, 0)
+        })
+    }
+
+    pub fn space_usage(&mut self) -> Result<SpaceUsage> {
+        let mut usage = SpaceUsage::default();
+        self.with_read_lock(|pager| {
+            let root_page_num = pager.header()?.root_page_num;
+            space_usage(pager, root_page_num, &mut usage)
+        })?;
+        Ok(usage)
+    }
+
+    // Everything wrong with the tree, or nothing if it is sound
+    pub fn integrity_check(&mut self) -> Result<Vec<String>> {
+        self.with_read_lock(|pager| {
+            let root_page_num = pager.header()?.root_page_num;
+            integrity_check(pager, root_page_num)
+        })
+    }
+
+    fn with_read_lock<T>(&mut self, f: impl FnOnce(&mut Pager) -> Result<T>) -> Result<T> {
+        self.begin_read()?;
+        let result = f(self.pager());
+        self.end_read()?;
+        result
     }
 
     pub fn root_page_num(&self) -> u32 {
         self.root_page_num
     }
 
+    // Points the header at a new root, which commits a copy-on-write change
+    pub fn set_root_page_num(&mut self, root_page_num: u32) -> Result<()> {
+        let pager = self.pager();
+        let mut header = pager.header()?;
+        header.root_page_num = root_page_num;
+        pager.write_header(&header)?;
+        self.root_page_num = root_page_num;
+        Ok(())
+    }
+
     pub fn pager(&mut self) -> &mut Pager {
         self.pager.as_mut().unwrap()
     }
+
+    // For reading through a table shared between threads
+    pub fn pager_ref(&self) -> &Pager {
+        self.pager.as_ref().unwrap()
+    }
 }
@@ -0,0 +1,108 @@
+// A backup is taken a step at a time while the database keeps changing, and must
+// still come out as the database was at one moment.
+
+mod common;
+
+use common::db_path;
+use my_sqlite::backup::Backup;
+use my_sqlite::header::StorageMode;
+use my_sqlite::{Connection, Value};
+
+fn insert(conn: &mut Connection, keys: impl Iterator<Item = i64>) {
+    for key in keys {
+        let username = Value::Text(format!("user{}", key));
+        let email = Value::Text(format!("person{}@example.com", key));
+        conn.execute("insert ? ? ?", &[Value::Integer(key), username, email])
+            .unwrap();
+    }
+}
+
+// The backup must hold exactly `keys`, in a sound tree
+fn check_backup(path: &str, keys: impl Iterator<Item = i64>) {
+    let mut conn = Connection::open_read_only(path).unwrap();
+    assert!(conn.table().integrity_check().unwrap().is_empty());
+    let ids: Vec<i64> = conn
+        .prepare("select")
+        .unwrap()
+        .query(&[])
+        .unwrap()
+        .map(|row| match row.unwrap()[0] {
+            Value::Integer(id) => id,
+            ref value => panic!("unexpected id {:?}", value),
+        })
+        .collect();
+    assert_eq!(ids, keys.collect::<Vec<_>>());
+    conn.close().unwrap();
+}
+
+fn run(name: &str, storage_mode: StorageMode) {
+    let source_path = db_path(&format!("{}-source", name));
+    let backup_path = db_path(&format!("{}-backup", name));
+
+    let mut conn = Connection::open_with_options(&source_path, 4096, storage_mode, false).unwrap();
+    insert(&mut conn, 1..=200);
+    conn.close().unwrap();
+
+    // Another connection writes back part way through, so the copy starts over
+    let mut source = Connection::open(&source_path).unwrap();
+    let mut backup = Backup::new(&backup_path).unwrap();
+    assert!(!backup.step(source.table(), 1).unwrap());
+    let mut writer = Connection::open(&source_path).unwrap();
+    insert(&mut writer, 201..=300);
+    writer.close().unwrap();
+    while !backup.step(source.table(), 2).unwrap() {}
+    assert_eq!(backup.restarts(), 1);
+    assert_eq!(backup.remaining(), 0);
+    check_backup(&backup_path, 1..=300);
+
+    // So does the source connection changing the database itself, and its changes
+    // are copied before they are written back
+    let _ = std::fs::remove_file(&backup_path);
+    let mut backup = Backup::new(&backup_path).unwrap();
+    assert!(!backup.step(source.table(), 1).unwrap());
+    insert(&mut source, 301..=350);
+    while !backup.step(source.table(), 2).unwrap() {}
+    assert_eq!(backup.restarts(), 1);
+    check_backup(&backup_path, 1..=350);
+
+    source.close().unwrap();
+    let _ = std::fs::remove_file(&source_path);
+    let _ = std::fs::remove_file(&backup_path);
+}
+
+#[test]
+fn backup_restarts_when_the_source_changes() {
+    run("backup_restarts", StorageMode::InPlace);
+}
+
+#[test]
+fn copy_on_write_backup_restarts_when_the_source_changes() {
+    run("cow_backup_restarts", StorageMode::CopyOnWrite);
+}
+
+#[test]
+fn backup_keeps_going_while_pages_are_read_into_the_cache() {
+    let source_path = db_path("backup_cache_reads-source");
+    let backup_path = db_path("backup_cache_reads-backup");
+    let mut conn = Connection::open(&source_path).unwrap();
+    insert(&mut conn, 1..=200);
+    conn.close().unwrap();
+
+    // Reading pages the copy hasn't reached yet loads them, but changes nothing
+    let mut source = Connection::open(&source_path).unwrap();
+    let mut backup = Backup::new(&backup_path).unwrap();
+    assert!(!backup.step(source.table(), 1).unwrap());
+    let pager = source.table().pager();
+    let data_version = pager.data_version();
+    for page_num in 1..pager.num_pages() {
+        pager.latched_page(page_num).unwrap();
+    }
+    assert_eq!(pager.data_version(), data_version);
+    while !backup.step(source.table(), 2).unwrap() {}
+    assert_eq!(backup.restarts(), 0);
+    check_backup(&backup_path, 1..=200);
+
+    source.close().unwrap();
+    let _ = std::fs::remove_file(&source_path);
+    let _ = std::fs::remove_file(&backup_path);
+}
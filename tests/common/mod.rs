@@ -0,0 +1,28 @@
+// Helpers shared by the integration tests. Each test file uses only some of them.
+#![allow(dead_code)]
+
+// Writers get keys from their own range of this many
+pub const KEYS_PER_RANGE: u32 = 1000;
+
+// A fresh path in the temp directory, unique to this test run
+pub fn db_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("{}-{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path.to_str().unwrap().to_string()
+}
+
+// Each writer inserts its own range in a scrambled order, so inserts land all over
+// the tree and split leaves while other writers are inserting next to them
+pub fn writer_keys(writer: u32, keys_per_writer: u32) -> Vec<u32> {
+    let mut keys: Vec<u32> = (1..=keys_per_writer)
+        .map(|i| writer * KEYS_PER_RANGE + i)
+        .collect();
+    let mut state = writer as u64 + 1;
+    for i in (1..keys.len()).rev() {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        keys.swap(i, (state >> 33) as usize % (i + 1));
+    }
+    keys
+}
@@ -0,0 +1,78 @@
+// Threads insert disjoint key ranges into one shared database while others query
+// it, then the tree is checked to still be a valid B-tree.
+
+mod common;
+
+use common::{db_path, writer_keys};
+use my_sqlite::header::StorageMode;
+use my_sqlite::{Connection, Database, Value};
+use std::thread;
+
+fn run(name: &str, page_size: u32, writers: u32, keys_per_writer: u32) {
+    let path = db_path(name);
+    let db = Database::open_with_options(&path, page_size, StorageMode::InPlace, false).unwrap();
+
+    thread::scope(|scope| {
+        for writer in 0..writers {
+            let db = db.clone();
+            scope.spawn(move || {
+                for key in writer_keys(writer, keys_per_writer) {
+                    let params = [Value::from(key), "user".into(), "email".into()];
+                    db.execute("insert ? ? ?", &params).unwrap();
+                }
+            });
+        }
+        for _ in 0..2 {
+            let db = db.clone();
+            scope.spawn(move || {
+                for _ in 0..50 {
+                    let ids: Vec<i64> = db
+                        .query("select", &[])
+                        .unwrap()
+                        .into_iter()
+                        .map(|row| match row[0] {
+                            Value::Integer(id) => id,
+                            _ => panic!("id is not an integer"),
+                        })
+                        .collect();
+                    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+            });
+        }
+    });
+    db.close().unwrap();
+
+    let mut conn = Connection::open(&path).unwrap();
+    assert!(conn.table().integrity_check().unwrap().is_empty());
+
+    let mut expected: Vec<i64> = (0..writers)
+        .flat_map(|writer| writer_keys(writer, keys_per_writer))
+        .map(i64::from)
+        .collect();
+    expected.sort();
+    let ids: Vec<i64> = conn
+        .prepare("select")
+        .unwrap()
+        .query(&[])
+        .unwrap()
+        .map(|row| match row.unwrap()[0] {
+            Value::Integer(id) => id,
+            _ => panic!("id is not an integer"),
+        })
+        .collect();
+    assert_eq!(ids, expected);
+    conn.close().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn concurrent_inserts_into_large_leaves() {
+    // Most inserts fit in their leaf and run side by side
+    run("concurrent-large-leaves", 16384, 8, 100);
+}
+
+#[test]
+fn concurrent_inserts_that_split_often() {
+    // Leaves hold 3 rows, so most inserts have to restart and split
+    run("concurrent-small-leaves", 1024, 4, 25);
+}
@@ -0,0 +1,256 @@
+// The statement API: preparing, binding parameters by position or name, and
+// reading rows back.
+
+mod common;
+
+use common::db_path;
+use my_sqlite::error::{BindError, ConstraintError};
+use my_sqlite::header::StorageMode;
+use my_sqlite::{Connection, DbError, Value};
+use std::time::Duration;
+
+fn user(id: u32) -> Vec<Value> {
+    vec![
+        Value::from(id),
+        format!("user{}", id).into(),
+        format!("person{}@example.com", id).into(),
+    ]
+}
+
+fn select_all(conn: &mut Connection) -> Vec<Vec<Value>> {
+    let mut statement = conn.prepare("select").unwrap();
+    let rows = statement.query(&[]).unwrap();
+    rows.collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn prepared_statements_run_with_new_parameters_each_time() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let mut insert = conn.prepare("insert ? ? ?").unwrap();
+    for id in [3, 1, 2] {
+        assert_eq!(insert.execute(&user(id)).unwrap(), 1);
+    }
+    assert_eq!(select_all(&mut conn), vec![user(1), user(2), user(3)]);
+
+    assert_eq!(
+        conn.execute("insert 4 user4 person4@example.com", &[])
+            .unwrap(),
+        1
+    );
+    assert_eq!(conn.execute("select", &[]).unwrap(), 0);
+    assert_eq!(conn.execute("savepoint one", &[]).unwrap(), 0);
+    assert_eq!(conn.execute("release one", &[]).unwrap(), 0);
+    assert!(matches!(
+        conn.execute("insert ? ? ?", &user(4)),
+        Err(DbError::Constraint(ConstraintError::DuplicateKey))
+    ));
+
+    // A statement that doesn't produce rows runs when queried and returns none
+    let mut insert = conn.prepare("insert ? ? ?").unwrap();
+    assert_eq!(insert.query(&user(5)).unwrap().count(), 0);
+    assert_eq!(select_all(&mut conn).len(), 5);
+}
+
+#[test]
+fn named_parameters_bind_by_name() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let mut insert = conn.prepare("insert :id :name :email").unwrap();
+    assert_eq!(insert.parameter_count(), 3);
+    let params = [
+        (":email", Value::from("person1@example.com")),
+        (":id", Value::from(1u32)),
+        (":name", Value::from("user1")),
+    ];
+    assert_eq!(insert.execute_named(&params).unwrap(), 1);
+
+    assert!(matches!(
+        insert.execute_named(&[(":id", 2u32.into()), (":name", "user2".into())]),
+        Err(DbError::Bind(BindError::Unbound(3)))
+    ));
+    assert!(matches!(
+        insert.execute_named(&[(":nope", 2u32.into())]),
+        Err(DbError::Bind(BindError::UnknownName(name))) if name == ":nope"
+    ));
+
+    let mut select = conn.prepare("select").unwrap();
+    let rows: Vec<_> = select
+        .query_named(&[])
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(rows, vec![user(1)]);
+}
+
+#[test]
+fn parameters_are_numbered_in_order_of_appearance() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let statement = conn.prepare("insert ? :name ?").unwrap();
+    assert_eq!(statement.parameter_count(), 3);
+    assert_eq!(statement.parameter_index(":name"), Some(2));
+    assert_eq!(statement.parameter_index(":email"), None);
+    drop(statement);
+
+    // A name used twice is one parameter, and `?NNN` claims every index up to it
+    let statement = conn.prepare("insert :id :id person@example.com").unwrap();
+    assert_eq!(statement.parameter_count(), 1);
+    drop(statement);
+    let statement = conn.prepare("insert ?3 user person@example.com").unwrap();
+    assert_eq!(statement.parameter_count(), 3);
+    drop(statement);
+
+    let statement = conn.prepare("select").unwrap();
+    assert_eq!(statement.parameter_count(), 0);
+    drop(statement);
+
+    assert!(matches!(
+        conn.execute("insert ? ? ?", &user(1)[..2]),
+        Err(DbError::Bind(BindError::ParameterCount {
+            expected: 3,
+            given: 2
+        }))
+    ));
+    assert!(matches!(
+        conn.execute(
+            "insert ? ? ?",
+            &[Value::from("one"), "a".into(), "b".into()]
+        ),
+        Err(DbError::Bind(BindError::Mismatch { column: "id" }))
+    ));
+}
+
+#[test]
+fn dropping_rows_early_releases_the_shared_lock() {
+    let path = db_path("rows-lock");
+    let path = path.as_str();
+
+    let mut reader = Connection::open(path).unwrap();
+    for id in 1..=3 {
+        reader.execute("insert ? ? ?", &user(id)).unwrap();
+    }
+    reader.close().unwrap();
+
+    let mut reader = Connection::open(path).unwrap();
+    let mut writer = Connection::open(path).unwrap();
+    writer.set_busy_timeout(Duration::from_millis(50));
+    {
+        let mut select = reader.prepare("select").unwrap();
+        let mut rows = select.query(&[]).unwrap();
+        assert_eq!(rows.next().unwrap().unwrap(), user(1));
+
+        // Writing back needs every reader gone
+        writer.execute("insert ? ? ?", &user(4)).unwrap();
+        assert!(matches!(writer.close(), Err(DbError::DatabaseBusy)));
+    }
+
+    // The rows were dropped part way through, which let go of the lock
+    let mut writer = Connection::open(path).unwrap();
+    writer.set_busy_timeout(Duration::from_millis(50));
+    writer.execute("insert ? ? ?", &user(5)).unwrap();
+    writer.close().unwrap();
+
+    let ids: Vec<_> = select_all(&mut reader)
+        .into_iter()
+        .map(|row| row[0].clone())
+        .collect();
+    assert_eq!(ids, [1u32, 2, 3, 5].map(Value::from));
+    reader.close().unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+// A statement run while another is reading, as a `Database` does for its
+// threads, must leave the shared lock to the reader that took it first
+fn assert_nested_read_keeps_lock(name: &str, nested: impl FnOnce(&mut Connection)) {
+    let path = db_path(name);
+    let mut conn = Connection::open(&path).unwrap();
+    for id in 1..=3 {
+        conn.execute("insert ? ? ?", &user(id)).unwrap();
+    }
+    conn.close().unwrap();
+
+    let mut reader = Connection::open(&path).unwrap();
+    let mut writer = Connection::open(&path).unwrap();
+    writer.set_busy_timeout(Duration::from_millis(50));
+    writer.execute("insert ? ? ?", &user(4)).unwrap();
+
+    reader.table().begin_read().unwrap();
+    nested(&mut reader);
+    assert!(matches!(
+        writer.table().pager().close(),
+        Err(DbError::DatabaseBusy)
+    ));
+    reader.table().end_read().unwrap();
+    writer.close().unwrap();
+
+    assert_eq!(select_all(&mut reader).len(), 4);
+    reader.close().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn integrity_check_inside_a_read_keeps_the_shared_lock() {
+    assert_nested_read_keeps_lock("nested-integrity-check", |conn| {
+        assert!(conn.table().integrity_check().unwrap().is_empty());
+    });
+}
+
+#[test]
+fn vacuum_into_inside_a_read_keeps_the_shared_lock() {
+    let copy = db_path("nested-vacuum-into-copy");
+    assert_nested_read_keeps_lock("nested-vacuum-into", |conn| {
+        conn.execute(&format!("vacuum into '{}'", copy), &[])
+            .unwrap();
+    });
+    let mut conn = Connection::open_read_only(&copy).unwrap();
+    assert_eq!(select_all(&mut conn).len(), 3);
+    conn.close().unwrap();
+    std::fs::remove_file(&copy).unwrap();
+}
+
+#[test]
+fn incremental_vacuum_inside_a_read_keeps_the_shared_lock() {
+    // Without incremental auto-vacuum it only reads the header
+    assert_nested_read_keeps_lock("nested-incremental-vacuum", |conn| {
+        conn.execute("pragma incremental_vacuum", &[]).unwrap();
+    });
+}
+
+#[test]
+fn space_usage_follows_a_root_moved_by_another_connection() {
+    let path = db_path("moved-root");
+    let mut conn =
+        Connection::open_with_options(&path, 4096, StorageMode::CopyOnWrite, false).unwrap();
+    for id in 1..=3 {
+        conn.execute("insert ? ? ?", &user(id)).unwrap();
+    }
+    conn.close().unwrap();
+
+    let mut reader = Connection::open(&path).unwrap();
+    assert_eq!(reader.table().space_usage().unwrap().rows, 3);
+
+    // Every copy-on-write commit puts the root on a new page
+    let mut writer = Connection::open(&path).unwrap();
+    for id in 4..=20 {
+        writer.execute("insert ? ? ?", &user(id)).unwrap();
+    }
+    writer.close().unwrap();
+
+    assert_eq!(reader.table().space_usage().unwrap().rows, 20);
+    reader.close().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn in_memory_copy_on_write_reuses_replaced_pages() {
+    let mut conn =
+        Connection::open_with_options(":memory:", 4096, StorageMode::CopyOnWrite, false).unwrap();
+    for id in 1..=3 {
+        conn.execute("insert ? ? ?", &user(id)).unwrap();
+    }
+    // The root moves between two pages, since nothing is written back to keep the
+    // one it left for
+    let pager = conn.table().pager();
+    assert_eq!(pager.num_pages(), 3);
+    assert_eq!(pager.reusable_pages_below(pager.num_pages()), 1);
+    assert_eq!(select_all(&mut conn), vec![user(1), user(2), user(3)]);
+}
@@ -0,0 +1,51 @@
+// A node claiming more cells than fit on its page must be reported as corrupt on
+// every path that reads it, not read past the end of the page.
+
+mod common;
+
+use common::db_path;
+use my_sqlite::checksum::write_checksum;
+use my_sqlite::{Database, DbError, Value};
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[test]
+fn overfull_leaf_is_corrupt() {
+    let path = db_path("overfull-leaf");
+    let path = path.as_str();
+
+    let db = Database::open(path).unwrap();
+    for key in 1..=3u32 {
+        let params = [Value::from(key), "user".into(), "email".into()];
+        db.execute("insert ? ? ?", &params).unwrap();
+    }
+    db.close().unwrap();
+
+    // The root is still a single leaf on page 1
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut page = vec![0; PAGE_SIZE as usize];
+    file.read_exact_at(&mut page, PAGE_SIZE).unwrap();
+    page[6..10].copy_from_slice(&1000u32.to_le_bytes());
+    write_checksum(&mut page);
+    file.write_all_at(&page, PAGE_SIZE).unwrap();
+    drop(file);
+
+    let db = Database::open(path).unwrap();
+    assert!(matches!(
+        db.query("select", &[]),
+        Err(DbError::Corrupt { page: 1 })
+    ));
+    let params = [Value::from(4u32), "user".into(), "email".into()];
+    assert!(matches!(
+        db.execute("insert ? ? ?", &params),
+        Err(DbError::Corrupt { page: 1 })
+    ));
+    db.close().unwrap();
+    std::fs::remove_file(path).unwrap();
+}
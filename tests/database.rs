@@ -0,0 +1,82 @@
+// One database shared between threads that insert and query it at the same time.
+
+mod common;
+
+use common::db_path;
+use my_sqlite::error::ConstraintError;
+use my_sqlite::header::StorageMode;
+use my_sqlite::{Database, DbError, Value};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+
+const THREADS: u32 = 4;
+const KEYS: u32 = 60;
+
+fn ids(db: &Database) -> Vec<i64> {
+    db.query("select", &[])
+        .unwrap()
+        .into_iter()
+        .map(|row| match row[0] {
+            Value::Integer(id) => id,
+            _ => panic!("id is not an integer"),
+        })
+        .collect()
+}
+
+// Every thread tries every key, each in its own order, so the same key is often
+// being inserted by one thread while another splits the leaf it belongs in
+#[test]
+fn duplicate_keys_racing_splits_are_inserted_once() {
+    let path = db_path("database-duplicates");
+    let path = path.as_str();
+    // Leaves hold 3 rows, so most inserts split one
+    let db = Database::open_with_options(path, 1024, StorageMode::InPlace, false).unwrap();
+    let inserted = AtomicU32::new(0);
+
+    thread::scope(|scope| {
+        for thread in 0..THREADS {
+            let db = db.clone();
+            let inserted = &inserted;
+            scope.spawn(move || {
+                let mut keys: Vec<u32> = (1..=KEYS).collect();
+                keys.rotate_left((thread * KEYS / THREADS) as usize);
+                if thread % 2 == 1 {
+                    keys.reverse();
+                }
+                for key in keys {
+                    let params = [Value::from(key), "user".into(), "email".into()];
+                    match db.execute("insert ? ? ?", &params) {
+                        Ok(1) => {
+                            inserted.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(DbError::Constraint(ConstraintError::DuplicateKey)) => {}
+                        other => panic!("insert {} gave {:?}", key, other),
+                    }
+                }
+            });
+        }
+        for _ in 0..2 {
+            let db = db.clone();
+            scope.spawn(move || {
+                for _ in 0..50 {
+                    let ids = ids(&db);
+                    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+            });
+        }
+    });
+
+    assert_eq!(inserted.load(Ordering::Relaxed), KEYS);
+    assert_eq!(ids(&db), (1..=KEYS as i64).collect::<Vec<_>>());
+    assert_eq!(
+        db.query("pragma integrity_check", &[]).unwrap(),
+        vec![vec![Value::from("ok")]]
+    );
+    db.close().unwrap();
+
+    // Everything committed is in the file once the last handle closes
+    let db = Database::open(path).unwrap();
+    assert_eq!(ids(&db).len(), KEYS as usize);
+    db.close().unwrap();
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,95 @@
+// Queries run while other threads insert, and each must see the database exactly
+// as it was at one commit.
+
+mod common;
+
+use common::{db_path, writer_keys, KEYS_PER_RANGE};
+use my_sqlite::header::StorageMode;
+use my_sqlite::{Database, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+fn run(name: &str, storage_mode: StorageMode, writers: u32, keys_per_writer: u32) {
+    let path = db_path(name);
+    let db = Database::open_with_options(&path, 4096, storage_mode, false).unwrap();
+    let inserting = AtomicBool::new(true);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..writers)
+            .map(|writer| {
+                let db = db.clone();
+                scope.spawn(move || {
+                    for key in writer_keys(writer, keys_per_writer) {
+                        let params = [Value::from(key), "user".into(), "email".into()];
+                        db.execute("insert ? ? ?", &params).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..3 {
+            let db = db.clone();
+            let inserting = &inserting;
+            scope.spawn(move || {
+                let mut scans = 0;
+                while inserting.load(Ordering::Acquire) || scans == 0 {
+                    check_snapshot(&db, writers, keys_per_writer);
+                    scans += 1;
+                }
+            });
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        inserting.store(false, Ordering::Release);
+    });
+
+    let rows = db.query("select", &[]).unwrap();
+    assert_eq!(rows.len(), (writers * keys_per_writer) as usize);
+    db.close().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+// Each writer commits its inserts one after another, so a consistent snapshot holds
+// some number of each writer's first inserts and nothing else
+fn check_snapshot(db: &Database, writers: u32, keys_per_writer: u32) {
+    let ids: Vec<u32> = db
+        .query("select", &[])
+        .unwrap()
+        .into_iter()
+        .map(|row| match row[0] {
+            Value::Integer(id) => id as u32,
+            _ => panic!("id is not an integer"),
+        })
+        .collect();
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+    for writer in 0..writers {
+        let range = writer * KEYS_PER_RANGE..(writer + 1) * KEYS_PER_RANGE;
+        let seen: Vec<u32> = ids
+            .iter()
+            .copied()
+            .filter(|id| range.contains(id))
+            .collect();
+        let mut committed = writer_keys(writer, keys_per_writer);
+        committed.truncate(seen.len());
+        committed.sort();
+        assert_eq!(seen, committed);
+    }
+}
+
+#[test]
+fn queries_see_one_writer_commit_by_commit() {
+    run("snapshot-one-writer", StorageMode::InPlace, 1, 400);
+}
+
+#[test]
+fn queries_see_concurrent_writers_commit_by_commit() {
+    run("snapshot-many-writers", StorageMode::InPlace, 4, 100);
+}
+
+#[test]
+fn queries_see_copy_on_write_commits() {
+    run("snapshot-copy-on-write", StorageMode::CopyOnWrite, 2, 100);
+}
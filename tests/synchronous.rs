@@ -0,0 +1,58 @@
+// Each synchronous level waits for the disk at a different set of points while
+// changes are written back at close.
+
+mod common;
+
+use common::db_path;
+use my_sqlite::header::StorageMode;
+use my_sqlite::pager::{Synchronous, DEFAULT_PAGE_SIZE};
+use my_sqlite::statement::prepare_row;
+use my_sqlite::table::Table;
+
+fn syncs_at_close(storage_mode: StorageMode, synchronous: Synchronous) -> u32 {
+    let path = db_path(&format!("synchronous-{:?}-{:?}", storage_mode, synchronous));
+    let path = path.as_str();
+
+    let mut table = Table::new();
+    table
+        .db_open(path, DEFAULT_PAGE_SIZE, storage_mode, false)
+        .unwrap();
+    table.pager().set_synchronous(synchronous);
+    let row = prepare_row("1", "user1", "person1@example.com").unwrap();
+    table.insert(row).unwrap();
+    // Nothing is written back before close, so nothing waits either
+    assert_eq!(table.pager().sync_count(), 0);
+
+    table.pager().close().unwrap();
+    let syncs = table.pager().sync_count();
+    table.db_close().unwrap();
+    std::fs::remove_file(path).unwrap();
+    syncs
+}
+
+#[test]
+fn off_never_syncs() {
+    assert_eq!(syncs_at_close(StorageMode::InPlace, Synchronous::Off), 0);
+    assert_eq!(
+        syncs_at_close(StorageMode::CopyOnWrite, Synchronous::Off),
+        0
+    );
+}
+
+#[test]
+fn normal_syncs_only_before_a_copy_on_write_header() {
+    assert_eq!(syncs_at_close(StorageMode::InPlace, Synchronous::Normal), 0);
+    assert_eq!(
+        syncs_at_close(StorageMode::CopyOnWrite, Synchronous::Normal),
+        1
+    );
+}
+
+#[test]
+fn full_also_syncs_once_everything_is_written() {
+    assert_eq!(syncs_at_close(StorageMode::InPlace, Synchronous::Full), 1);
+    assert_eq!(
+        syncs_at_close(StorageMode::CopyOnWrite, Synchronous::Full),
+        2
+    );
+}